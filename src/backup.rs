@@ -0,0 +1,425 @@
+//! Backup and restore of the KV store
+//!
+//! A backup directory holds a chain of backups: one full backup followed by any number of
+//! incremental ones, and a manifest describing them. The full backup is a compacted copy of the
+//! log data file, written from the in-memory map so it is consistent regardless of what is on
+//! disk. An incremental backup only holds the records needed to go from the state of the
+//! previous backup to the current one. Each backup gets the next sequence number of the chain.
+//! The manifest keeps a SHA-256 digest of every value as of the last backup, so the changes are
+//! found without reading the chain back.
+//!
+//! Every backup is written to a file no manifest refers to yet, and the manifest is replaced
+//! atomically after the backup's data is synced, so a backup interrupted half way is not part
+//! of the chain, and `KvStore::restore` verifies every backup it replays against the manifest.
+//! A new full backup gets a file name of its own, the previous chain's files are only removed
+//! once the manifest describes the new chain.
+use super::record::write_log_file;
+use super::{
+    lock_dir, now, read_command, write_command, Command, Error, KvStore, Result, LOCK_FILE_NAME,
+    LOG_DATA_FILE_NAME,
+};
+use crc32fast::Hasher;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::path::Path;
+
+/// Backup manifest's file name
+const BACKUP_MANIFEST_FILE_NAME: &str = "backup.manifest";
+
+/// Temporary backup manifest's file name used while replacing the manifest
+const BACKUP_MANIFEST_TMP_FILE_NAME: &str = "backup.manifest.tmp";
+
+/// Suffix of a backup data file's name while it is written
+const BACKUP_TMP_SUFFIX: &str = ".tmp";
+
+/// Temporary log data file's name used while restoring
+const RESTORE_TMP_FILE_NAME: &str = "log.data.restore";
+
+/// Describes the chain of backups in a backup directory, oldest first
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupManifest {
+    /// Number of full backups taken in the directory, naming the current one's data file
+    generation: u64,
+    entries: Vec<BackupEntry>,
+    /// Digest of the value of every key as of the last backup of the chain
+    state: HashMap<String, Digest>,
+}
+
+/// Describes one backup's data file
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupEntry {
+    /// Sequence number in the chain, the full backup is 1
+    seq: u64,
+    /// Seconds since the Unix epoch when the backup was taken
+    created_at: u64,
+    /// Data file's name in the backup directory
+    file_name: String,
+    /// Number of records in the data file
+    records: u64,
+    /// Length of the data file in bytes
+    len: u64,
+    /// CRC32 checksum of the data file
+    checksum: u32,
+}
+
+/// Summary of one backup in a backup directory
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BackupInfo {
+    /// Sequence number in the chain, the full backup is 1
+    pub seq: u64,
+    /// Seconds since the Unix epoch when the backup was taken
+    pub created_at: u64,
+    /// Whether the backup only holds the changes since the previous one
+    pub incremental: bool,
+    /// Number of records in the backup
+    pub records: u64,
+}
+
+/// How far to replay a chain of backups when restoring, always to a whole backup
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestorePoint {
+    /// Replay every backup
+    Latest,
+    /// Replay the backups up to and including the given sequence number
+    Seq(u64),
+    /// Replay the backups taken at or before the given time, in seconds since the Unix epoch
+    Time(u64),
+}
+
+/// Wraps a reader or a writer, counting and checksumming the bytes passing through
+struct Checksummed<T> {
+    inner: T,
+    hasher: Hasher,
+    len: u64,
+}
+
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Self {
+        Checksummed {
+            inner,
+            hasher: Hasher::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+/// Read the manifest in the backup directory `dir`
+///
+/// Return `Ok(manifest)` if success,
+/// return `Err(Error::InvalidBackup)` if the manifest is malformed,
+/// return `Err(Error::File)` if it can't be read, missing included
+fn read_manifest(dir: &Path) -> Result<BackupManifest> {
+    let manifest_path = dir.join(BACKUP_MANIFEST_FILE_NAME);
+    let bytes = fs::read(&manifest_path).map_err(|err| Error::from(err).at(&manifest_path))?;
+    bincode::deserialize(&bytes)
+        .map_err(|err| Error::InvalidBackup(format!("malformed backup manifest: {}", err)))
+}
+
+/// Atomically replace the manifest in the backup directory `dir`
+fn write_manifest(dir: &Path, manifest: &BackupManifest) -> Result<()> {
+    let tmp_path = dir.join(BACKUP_MANIFEST_TMP_FILE_NAME);
+    let encoded = bincode::serialize(manifest)?;
+    let mut file = File::create(&tmp_path).map_err(|err| Error::from(err).at(&tmp_path))?;
+    file.write_all(&encoded)
+        .and_then(|_| file.sync_all())
+        .map_err(|err| Error::from(err).at(&tmp_path))?;
+    let manifest_path = dir.join(BACKUP_MANIFEST_FILE_NAME);
+    fs::rename(&tmp_path, &manifest_path).map_err(|err| Error::from(err).at(&manifest_path))
+}
+
+/// Digest of a value recorded in the manifest
+type Digest = [u8; 32];
+
+/// Return the digest of `value` recorded in the manifest, its SHA-256 hash
+///
+/// A collision-resistant hash, so a changed value is never taken for the one backed up.
+fn digest(value: &str) -> Digest {
+    Sha256::digest(value.as_bytes()).into()
+}
+
+/// Return the digests of every value of `map`, as recorded in the manifest
+fn digest_state(map: &HashMap<String, String>) -> HashMap<String, Digest> {
+    map.iter()
+        .map(|(key, value)| (key.clone(), digest(value)))
+        .collect()
+}
+
+/// Check that `dir` can hold backups, which a store's data directory can't
+///
+/// Return `Err(Error::NotBackupDir)` if `dir` holds a lock or a log data file
+fn check_backup_dir(dir: &Path) -> Result<()> {
+    if dir.join(LOCK_FILE_NAME).exists() || dir.join(LOG_DATA_FILE_NAME).exists() {
+        return Err(Error::NotBackupDir(dir.to_owned()));
+    }
+    Ok(())
+}
+
+/// Write `commands` into a new backup data file in `dir`, synced to disk
+///
+/// The data is written under a temporary name first, so `file_name` either holds the whole
+/// backup or isn't touched.
+///
+/// Return the entry describing it, without sequence number
+fn write_backup_file<I>(dir: &Path, file_name: &str, commands: I) -> Result<BackupEntry>
+where
+    I: IntoIterator<Item = Command>,
+{
+    let tmp_path = dir.join(format!("{}{}", file_name, BACKUP_TMP_SUFFIX));
+    let file = File::create(&tmp_path).map_err(|err| Error::from(err).at(&tmp_path))?;
+    let mut writer = Checksummed::new(BufWriter::new(file));
+    let mut records = 0;
+    for command in commands {
+        write_command(&mut writer, &command).map_err(|err| err.at(&tmp_path))?;
+        records += 1;
+    }
+    writer
+        .flush()
+        .map_err(|err| Error::from(err).at(&tmp_path))?;
+    let Checksummed { inner, hasher, len } = writer;
+    inner
+        .get_ref()
+        .sync_all()
+        .map_err(|err| Error::from(err).at(&tmp_path))?;
+    let file_path = dir.join(file_name);
+    fs::rename(&tmp_path, &file_path).map_err(|err| Error::from(err).at(&file_path))?;
+
+    Ok(BackupEntry {
+        seq: 0,
+        created_at: now(),
+        file_name: file_name.to_owned(),
+        records,
+        len,
+        checksum: hasher.finalize(),
+    })
+}
+
+/// Verify the backup `entry` in `dir` against the manifest and apply its records to `map`
+fn replay_backup(dir: &Path, entry: &BackupEntry, map: &mut HashMap<String, String>) -> Result<()> {
+    let path = dir.join(&entry.file_name);
+    let file = File::open(&path).map_err(|err| Error::from(err).at(&path))?;
+    let mut reader = Checksummed::new(BufReader::new(file));
+    let mut changes = Vec::new();
+    loop {
+        let offset = reader.len;
+        match read_command(&mut reader) {
+            Ok(Some((command, _))) => changes.push(command),
+            Ok(None) => break,
+            Err(err) => {
+                return Err(Error::BadBackupRecord {
+                    path,
+                    offset,
+                    source: Box::new(err),
+                });
+            }
+        }
+    }
+    if changes.len() as u64 != entry.records
+        || reader.len != entry.len
+        || reader.hasher.finalize() != entry.checksum
+    {
+        return Err(Error::InvalidBackup(format!(
+            "backup {} doesn't match the backup manifest",
+            entry.seq
+        )));
+    }
+
+    for command in changes.into_iter().flat_map(Command::into_commands) {
+        match command {
+            Command::Set { key, value } => {
+                map.insert(key, value);
+            }
+            Command::Remove { key } => {
+                map.remove(&key);
+            }
+            Command::Get { .. } | Command::Batch { .. } => {}
+        }
+    }
+    Ok(())
+}
+
+impl KvStore {
+    /// Take a full backup of the KV store into the directory `dest`
+    ///
+    /// The store stays usable afterwards. An existing chain of backups in `dest` is replaced
+    /// once the new full backup is complete.
+    ///
+    /// Return `Ok` if success,
+    /// return `Err(Error::NotBackupDir)` if `dest` is a store's data directory,
+    /// return `Err` when other error occurs
+    pub fn backup(&self, dest: &Path) -> Result<()> {
+        check_backup_dir(dest)?;
+        fs::create_dir_all(dest).map_err(|err| Error::from(err).at(dest))?;
+        let old_manifest = read_manifest(dest).ok();
+
+        let generation = old_manifest
+            .as_ref()
+            .map_or(0, |manifest| manifest.generation)
+            + 1;
+        let file_name = format!("full-{:010}.data", generation);
+        let commands = self.key_value_map.iter().map(|(key, value)| Command::Set {
+            key: key.clone(),
+            value: value.clone(),
+        });
+        let mut entry = write_backup_file(dest, &file_name, commands)?;
+        entry.seq = 1;
+        write_manifest(
+            dest,
+            &BackupManifest {
+                generation,
+                entries: vec![entry],
+                state: digest_state(&self.key_value_map),
+            },
+        )?;
+
+        if let Some(old_manifest) = old_manifest {
+            for old_entry in old_manifest.entries.iter() {
+                let old_path = dest.join(&old_entry.file_name);
+                fs::remove_file(&old_path).map_err(|err| Error::from(err).at(&old_path))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take an incremental backup of the KV store into the directory `dest`
+    ///
+    /// `dest` must already hold a chain of backups started by `KvStore::backup`. Only the
+    /// records needed to bring the state of the last backup up to date are written, found by
+    /// comparing the store with the digests in the manifest.
+    ///
+    /// Return `Ok(seq)` the new backup's sequence number if success,
+    /// return `Err(Error::InvalidBackup)` if the manifest in `dest` is malformed,
+    /// return `Err(Error::File)` if `dest` holds no manifest,
+    /// return `Err(Error::NotBackupDir)` if `dest` is a store's data directory,
+    /// return `Err` when other error occurs
+    pub fn backup_incremental(&self, dest: &Path) -> Result<u64> {
+        check_backup_dir(dest)?;
+        let mut manifest = read_manifest(dest)?;
+
+        let state = digest_state(&self.key_value_map);
+        let mut commands = Vec::new();
+        for (key, value) in self.key_value_map.iter() {
+            if manifest.state.get(key) != state.get(key) {
+                commands.push(Command::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+        for key in manifest.state.keys() {
+            if !self.key_value_map.contains_key(key) {
+                commands.push(Command::Remove { key: key.clone() });
+            }
+        }
+
+        let seq = manifest.entries.last().map_or(0, |entry| entry.seq) + 1;
+        let file_name = format!("incr-{:010}.data", seq);
+        let mut entry = write_backup_file(dest, &file_name, commands)?;
+        entry.seq = seq;
+        manifest.entries.push(entry);
+        manifest.state = state;
+        write_manifest(dest, &manifest)?;
+
+        Ok(seq)
+    }
+
+    /// List the chain of backups in the directory `backup`, oldest first
+    pub fn list_backups(backup: &Path) -> Result<Vec<BackupInfo>> {
+        let manifest = read_manifest(backup)?;
+        Ok(manifest
+            .entries
+            .iter()
+            .map(|entry| BackupInfo {
+                seq: entry.seq,
+                created_at: entry.created_at,
+                incremental: entry.seq > 1,
+                records: entry.records,
+            })
+            .collect())
+    }
+
+    /// Restore every backup in the directory `backup` into the directory `dest`
+    ///
+    /// Same as `KvStore::restore_until` with `RestorePoint::Latest`.
+    pub fn restore(backup: &Path, dest: &Path) -> Result<()> {
+        KvStore::restore_until(backup, dest, RestorePoint::Latest)
+    }
+
+    /// Restore the backups in the directory `backup` up to `point` into the directory `dest`
+    ///
+    /// Every backup replayed is verified against the manifest before anything in `dest` is
+    /// touched, then the log data file in `dest` is replaced atomically.
+    ///
+    /// The store is restored to its state as of one of the backups: the writes between two
+    /// backups are not kept one by one, so `point` can't fall in between.
+    ///
+    /// Return `Ok` if success,
+    /// return `Err(Error::InvalidBackup)` if a backup is incomplete or inconsistent, or if no
+    /// backup was taken at or before `point`,
+    /// return `Err(Error::BadBackupRecord)` if a record of a backup is truncated or undecodable,
+    /// return `Err(Error::File)` if `backup` holds no manifest,
+    /// return `Err(Error::NotStoreDir)` if `dest` holds backups, `backup` included,
+    /// return `Err(Error::Locked)` if a store has the directory `dest` open,
+    /// return `Err` when other error occurs
+    pub fn restore_until(backup: &Path, dest: &Path, point: RestorePoint) -> Result<()> {
+        if dest.join(BACKUP_MANIFEST_FILE_NAME).exists() {
+            return Err(Error::NotStoreDir(dest.to_owned()));
+        }
+        let manifest = read_manifest(backup)?;
+        let entries: Vec<&BackupEntry> = manifest
+            .entries
+            .iter()
+            .take_while(|entry| match point {
+                RestorePoint::Latest => true,
+                RestorePoint::Seq(seq) => entry.seq <= seq,
+                RestorePoint::Time(time) => entry.created_at <= time,
+            })
+            .collect();
+        if entries.is_empty() {
+            return Err(Error::InvalidBackup(format!(
+                "no backup at or before {:?}",
+                point
+            )));
+        }
+
+        let mut map = HashMap::new();
+        for entry in entries {
+            replay_backup(backup, entry, &mut map)?;
+        }
+
+        fs::create_dir_all(dest).map_err(|err| Error::from(err).at(dest))?;
+        let _lock = lock_dir(dest)?;
+        let commands: Vec<Command> = map
+            .into_iter()
+            .map(|(key, value)| Command::Set { key, value })
+            .collect();
+        write_log_file(dest, RESTORE_TMP_FILE_NAME, &commands)?;
+
+        Ok(())
+    }
+}
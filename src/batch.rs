@@ -0,0 +1,115 @@
+//! Batches of commands applied together
+use super::{write_command, Command, Error, KvStore, Result, LOG_DATA_FILE_NAME};
+use std::collections::HashMap;
+use std::io::{self, prelude::*};
+
+impl KvStore {
+    /// Apply `commands` in order as one batch
+    ///
+    /// Every command is checked against the state left by the ones before it before anything
+    /// is written, so a batch removing a key which doesn't exist at that point leaves the
+    /// store untouched. The `Set` and `Remove` commands are then appended to the log as one
+    /// checksummed `Command::Batch` record, synced to disk, so replaying the log applies all of
+    /// them or none. The log is then compacted once, rather than once per key as `set` does,
+    /// replacing it whole so a crash meanwhile leaves the batch record in place.
+    ///
+    /// Return `Ok(results)` with one entry per command: the value for `Get`, the removed value
+    /// for `Remove` and `None` for `Set` and `Batch`,
+    /// return `Err(Error::KeyNotFound)` when removing a non-existent key,
+    /// return `Err(Error::BatchTooLarge)` if the batch doesn't fit in one record,
+    /// return `Err` when other error occurs
+    pub fn apply_batch(&mut self, commands: &[Command]) -> Result<Vec<Option<String>>> {
+        let results = self.append_batch(commands)?;
+        self.compact_log_file()?;
+        Ok(results)
+    }
+
+    /// Apply `commands` as `KvStore::apply_batch` does, without compacting the log
+    ///
+    /// Lets several batches be appended before compacting once.
+    pub(crate) fn append_batch(&mut self, commands: &[Command]) -> Result<Vec<Option<String>>> {
+        let mut results = Vec::with_capacity(commands.len());
+        {
+            let mut staged: HashMap<&str, Option<&str>> = HashMap::new();
+            for command in commands {
+                results.push(self.stage(&mut staged, command)?);
+            }
+        }
+
+        let writes: Vec<Command> = commands
+            .iter()
+            .cloned()
+            .flat_map(Command::into_commands)
+            .filter(|command| !matches!(command, Command::Get { .. }))
+            .collect();
+        if writes.is_empty() {
+            return Ok(results);
+        }
+
+        let log_path = self.path.join(LOG_DATA_FILE_NAME);
+        let mut batch = Vec::new();
+        write_command(&mut batch, &Command::Batch { commands: writes })?;
+        let log_offset = self
+            .log_file
+            .seek(io::SeekFrom::End(0))
+            .map_err(|err| Error::from(err).at(&log_path))?;
+        self.log_file
+            .write_all(&batch)
+            .map_err(|err| Error::from(err).at(&log_path))?;
+        self.log_file
+            .flush()
+            .and_then(|_| self.log_file.sync_all())
+            .map_err(|err| Error::from(err).at(&log_path))?;
+        for command in commands.iter().cloned().flat_map(Command::into_commands) {
+            match command {
+                Command::Set { key, value } => {
+                    self.key_offset_map.insert(key.clone(), log_offset);
+                    self.key_value_map.insert(key, value);
+                }
+                Command::Remove { key } => {
+                    self.key_offset_map.remove(&key);
+                    self.key_value_map.remove(&key);
+                }
+                Command::Get { .. } | Command::Batch { .. } => {}
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Check `command` against the state left by the commands `staged` before it, and stage it
+    ///
+    /// Return `Ok(result)` the result of the command as returned by `KvStore::apply_batch`,
+    /// return `Err(Error::KeyNotFound)` when removing a non-existent key
+    fn stage<'a>(
+        &self,
+        staged: &mut HashMap<&'a str, Option<&'a str>>,
+        command: &'a Command,
+    ) -> Result<Option<String>> {
+        match command {
+            Command::Set { key, value } => {
+                staged.insert(key, Some(value));
+                Ok(None)
+            }
+            Command::Get { key } | Command::Remove { key } => {
+                let current = match staged.get(key.as_str()) {
+                    Some(value) => *value,
+                    None => self.key_value_map.get(key).map(String::as_str),
+                };
+                if let Command::Remove { .. } = command {
+                    if current.is_none() {
+                        return Err(Error::KeyNotFound(key.clone()));
+                    }
+                    staged.insert(key, None);
+                }
+                Ok(current.map(str::to_owned))
+            }
+            Command::Batch { commands } => {
+                for command in commands {
+                    self.stage(staged, command)?;
+                }
+                Ok(None)
+            }
+        }
+    }
+}
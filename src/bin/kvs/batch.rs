@@ -0,0 +1,109 @@
+//! Commands read one per line from a file or stdin
+use crate::line::{print_value, split_words, LineCommand};
+use crate::output::{
+    exit_code, print_error, OutputFormat, EXIT_NOT_FOUND, EXIT_SUCCESS, EXIT_USAGE,
+};
+use kvs::{self, Command, KvStore};
+use std::io::BufRead;
+
+/// Run the commands read from `reader` against `store`, printing their results in `output`
+///
+/// Blank lines and lines starting with `#` are skipped. Without `atomic`, each command runs as
+/// it is read, and a failing one is reported on stderr with its line number before going on.
+/// With `atomic`, every line is parsed first, then the whole batch goes through
+/// `KvStore::apply_batch` so either every command succeeds or the store is left untouched;
+/// `scan` isn't supported then. Either way, a get finding no such key is reported on stderr
+/// like a failing command.
+///
+/// Return `Ok(code)` the exit code of the first failing command, or `EXIT_SUCCESS`,
+/// return `Err` if reading the commands or writing the results fails, or the batch fails to apply
+pub fn run<R: BufRead>(
+    store: &mut KvStore,
+    reader: R,
+    atomic: bool,
+    output: OutputFormat,
+) -> kvs::Result<i32> {
+    let mut commands = Vec::new();
+    let mut line_numbers = Vec::new();
+    let mut status = EXIT_SUCCESS;
+    let mut fail = |message: String, code: i32| {
+        print_error(&message, code, output);
+        if status == EXIT_SUCCESS {
+            status = code;
+        }
+    };
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line_number = index + 1;
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let command = match split_words(&line).and_then(|words| LineCommand::parse(&words)) {
+            Ok(command) => command,
+            Err(msg) => {
+                fail(format!("line {}: {}", line_number, msg), EXIT_USAGE);
+                continue;
+            }
+        };
+
+        if !atomic {
+            if let Err(err) = command.execute(store, output) {
+                fail(format!("line {}: {}", line_number, err), exit_code(&err));
+            }
+            continue;
+        }
+        match command {
+            LineCommand::Get { key } => commands.push(Command::Get { key }),
+            LineCommand::Set { key, value } => commands.push(Command::Set { key, value }),
+            LineCommand::Remove { key } => commands.push(Command::Remove { key }),
+            LineCommand::Scan { .. } => {
+                fail(
+                    format!(
+                        "line {}: scan isn't supported in atomic batches",
+                        line_number
+                    ),
+                    EXIT_USAGE,
+                );
+                continue;
+            }
+        }
+        line_numbers.push(line_number);
+    }
+    if !atomic {
+        return Ok(status);
+    }
+    if status != EXIT_SUCCESS {
+        print_error("batch not applied", status, output);
+        return Ok(status);
+    }
+
+    let results = match store.apply_batch(&commands) {
+        Ok(results) => results,
+        Err(err @ kvs::Error::KeyNotFound(_)) => {
+            print_error(
+                &format!("batch not applied: {}", err),
+                EXIT_NOT_FOUND,
+                output,
+            );
+            return Ok(EXIT_NOT_FOUND);
+        }
+        Err(err) => return Err(err),
+    };
+    for ((command, line_number), result) in commands.into_iter().zip(line_numbers).zip(results) {
+        if let Command::Get { key } = command {
+            match result {
+                Some(value) => print_value(&key, &value, output)?,
+                None => {
+                    let err = kvs::Error::KeyNotFound(key);
+                    print_error(
+                        &format!("line {}: {}", line_number, err),
+                        EXIT_NOT_FOUND,
+                        output,
+                    );
+                    status = EXIT_NOT_FOUND;
+                }
+            }
+        }
+    }
+    Ok(status)
+}
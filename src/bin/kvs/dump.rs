@@ -0,0 +1,64 @@
+//! Record-by-record listing of the log
+use kvs::{self, Command, LogReader, LogRecord};
+use serde_json::json;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Print every record of the log in `path`, one per line, as tab separated text or as JSON
+///
+/// Each line holds the record's offset, length, command type, key and value. A record whose
+/// command can't be deserialized has type `undecodable`, and a truncated record ends the
+/// listing with type `truncated`. A batch has type `batch`, without key nor value.
+///
+/// Return `Ok` if every record was listed, even bad ones,
+/// return `Err` if the log's header is bad or reading the log fails
+pub fn run(path: &Path, json: bool) -> kvs::Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut reader = LogReader::open(path)?;
+    while let Some(record) = reader.next() {
+        let (offset, len, kind, key, value) = match &record {
+            Ok(LogRecord {
+                offset,
+                len,
+                command,
+            }) => {
+                let (kind, key, value) = match command {
+                    Some(Command::Set { key, value }) => ("set", Some(key), Some(value)),
+                    Some(Command::Get { key }) => ("get", Some(key), None),
+                    Some(Command::Remove { key }) => ("rm", Some(key), None),
+                    Some(Command::Batch { .. }) => ("batch", None, None),
+                    None => ("undecodable", None, None),
+                };
+                (*offset, Some(*len), kind, key, value)
+            }
+            Err(kvs::Error::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                (reader.offset(), None, "truncated", None, None)
+            }
+            Err(_) => return record.map(|_| ()),
+        };
+
+        if json {
+            let line = json!({
+                "offset": offset,
+                "len": len,
+                "type": kind,
+                "key": key,
+                "value": value,
+            });
+            writeln!(out, "{}", line)?;
+        } else {
+            let len = len.map(|len| len.to_string());
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}",
+                offset,
+                len.as_ref().map_or("-", String::as_str),
+                kind,
+                key.map_or("", String::as_str),
+                value.map_or("", String::as_str)
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,123 @@
+//! Parsing and execution of commands typed one per line, as accepted by `kvs shell` and
+//! `kvs batch`
+use crate::output::{print_json, OutputFormat};
+use kvs::{self, KvStore};
+use serde_json::json;
+
+/// A command parsed from one line of input
+#[derive(Debug, PartialEq)]
+pub enum LineCommand {
+    Get { key: String },
+    Set { key: String, value: String },
+    Remove { key: String },
+    Scan { prefix: String },
+}
+
+/// Names of the commands, in the order they're documented
+pub const COMMAND_NAMES: &[&str] = &["get", "set", "rm", "scan"];
+
+/// Usage of the commands, one per line
+pub const USAGE: &str = "get <KEY>          Get the string value of a given string key
+set <KEY> <VALUE>  Set the value of a string key to a string
+rm <KEY>           Remove a given key
+scan [PREFIX]      List the key-value pairs whose key starts with PREFIX";
+
+impl LineCommand {
+    /// Parse the words of a line, as returned by `split_words`
+    ///
+    /// Return `Err` with a message for the user if the words aren't a valid command
+    pub fn parse(words: &[String]) -> Result<LineCommand, String> {
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (name.as_str(), args),
+            None => return Err("missing command".to_owned()),
+        };
+        match (name, args) {
+            ("get", [key]) => Ok(LineCommand::Get { key: key.clone() }),
+            ("set", [key, value]) => Ok(LineCommand::Set {
+                key: key.clone(),
+                value: value.clone(),
+            }),
+            ("rm", [key]) => Ok(LineCommand::Remove { key: key.clone() }),
+            ("scan", []) => Ok(LineCommand::Scan {
+                prefix: String::new(),
+            }),
+            ("scan", [prefix]) => Ok(LineCommand::Scan {
+                prefix: prefix.clone(),
+            }),
+            (name, _) if COMMAND_NAMES.contains(&name) => {
+                Err(format!("wrong number of arguments for {}", name))
+            }
+            (name, _) => Err(format!("unknown command {}", name)),
+        }
+    }
+
+    /// Run the command against `store`, printing its result to stdout in `output`
+    ///
+    /// A get prints the value and a scan prints the matching pairs. Set and rm print nothing.
+    ///
+    /// Return `Err(kvs::Error::KeyNotFound)` if a get or an rm finds no such key,
+    /// return `Err` when other error occurs
+    pub fn execute(self, store: &mut KvStore, output: OutputFormat) -> kvs::Result<()> {
+        match self {
+            LineCommand::Get { key } => match store.get(key.clone())? {
+                Some(value) => print_value(&key, &value, output),
+                None => Err(kvs::Error::KeyNotFound(key)),
+            },
+            LineCommand::Set { key, value } => store.set(key, value),
+            LineCommand::Remove { key } => store.remove(key).map(|_| ()),
+            LineCommand::Scan { prefix } => {
+                for (key, value) in store.scan(&prefix) {
+                    match output {
+                        OutputFormat::Text => println!("{}\t{}", key, value),
+                        OutputFormat::Json => print_json(&json!({ "key": key, "value": value }))?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Print the `value` of `key` to stdout in `output`
+pub fn print_value(key: &str, value: &str, output: OutputFormat) -> kvs::Result<()> {
+    match output {
+        OutputFormat::Text => println!("{}", value),
+        OutputFormat::Json => print_json(&json!({ "key": key, "value": value }))?,
+    }
+    Ok(())
+}
+
+/// Split `line` into words separated by whitespace
+///
+/// Single or double quotes group characters, whitespace included, into one word. Outside
+/// single quotes a backslash takes the next character literally.
+///
+/// Return `Err` with a message for the user if a quote or an escape is left open
+pub fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+            (_, '\\') => match chars.next() {
+                Some(escaped) => word.get_or_insert_with(String::new).push(escaped),
+                None => return Err("unterminated escape".to_owned()),
+            },
+            (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(q) = quote {
+        return Err(format!("unterminated {} quote", q));
+    }
+    words.extend(word);
+    Ok(words)
+}
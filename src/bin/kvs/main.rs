@@ -0,0 +1,460 @@
+/// How the log behaves
+///
+/// The log is a record of the transactions committed to the database. By "replaying" the records in the log on startup we reconstruct the previous state of the database.
+///
+/// "set"
+///     The user invokes kvs set mykey myvalue
+///     kvs creates a value representing the "set" command, containing its key and value
+///     It then serializes that command to a String
+///     It then appends the serialized command to a file containing the log
+///     If that succeeds, it exits silently with error code 0
+///     If it fails, it exits by printing the error and returning a non-zero error code
+///
+/// "get"
+///     The user invokes kvs get mykey
+///     kvs reads the entire log, one command at a time, recording the affected key and file offset of the command to an in-memory key -> log pointer map
+///     It then checks the map for the log pointer
+///     If it fails, it prints "Key not found" to stderr, and exits with exit code 1
+///     If it succeeds
+///         It deserializes the command to get the last recorded value of the key
+///         It prints the value to stdout and exits with exit code 0
+///
+/// "rm"
+///     The user invokes kvs rm mykey
+///     Same as the "get" command, kvs reads the entire log to build the in-memory index
+///     It then checks the map if the given key exists
+///     If the key does not exist, it prints "Key not found" to stderr, and exits with exit code 1
+///     If it succeeds
+///         It creates a value representing the "rm" command, containing its key
+///         It then appends the serialized command to the log
+///         If that succeeds, it exits silently with error code 0
+///
+/// Exit codes
+///     0   Success
+///     1   The key does not exist
+///     2   The arguments or the input are invalid
+///     3   Reading or writing a file failed, or the store is in use by another process
+///     4   The log or a backup is corrupted
+///
+/// With `--output json`, results are printed to stdout as JSON objects, one per line, and
+/// errors to stderr as `{"error": ..., "kind": ..., "code": ...}` objects.
+extern crate structopt;
+mod batch;
+mod dump;
+mod line;
+mod output;
+mod shell;
+
+use kvs::{self, DataFormat, ImportMode, KvStore, RestorePoint, Result};
+use output::{
+    exit_code, print_error, print_json, OutputFormat, EXIT_CORRUPTION, EXIT_SUCCESS, EXIT_USAGE,
+};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::process;
+use std::result;
+
+#[derive(StructOpt, Debug)]
+#[structopt()]
+struct Opt {
+    #[structopt(
+        long = "dir",
+        env = "KVS_DIR",
+        default_value = "./",
+        parse(from_os_str),
+        raw(global = "true"),
+        help = "Directory holding the store's data, created if missing"
+    )]
+    dir: PathBuf,
+
+    #[structopt(
+        long = "output",
+        default_value = "text",
+        raw(possible_values = "&[\"text\", \"json\"]", global = "true"),
+        help = "Print results and errors as text or as JSON objects"
+    )]
+    output: OutputFormat,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "set", about = "Set the value of a string key to a string")]
+    Set {
+        #[structopt(name = "KEY")]
+        key: String,
+
+        #[structopt(name = "VALUE")]
+        value: String,
+    },
+
+    #[structopt(name = "get", about = "Get the string value of a given string key")]
+    Get {
+        #[structopt(name = "KEY")]
+        key: String,
+    },
+
+    #[structopt(name = "rm", about = "Remove a given key")]
+    Remove {
+        #[structopt(name = "KEY")]
+        key: String,
+    },
+
+    #[structopt(name = "backup", about = "Back up the store into a directory")]
+    Backup {
+        #[structopt(name = "DIR", parse(from_os_str))]
+        dir: PathBuf,
+
+        #[structopt(
+            long = "incremental",
+            help = "Only back up the changes since the previous backup in DIR"
+        )]
+        incremental: bool,
+    },
+
+    #[structopt(name = "backups", about = "List the backups in a backup directory")]
+    Backups {
+        #[structopt(name = "DIR", parse(from_os_str))]
+        dir: PathBuf,
+    },
+
+    #[structopt(
+        name = "restore",
+        about = "Restore the store from a backup directory",
+        after_help = "The store can only be restored to its state as of one of the backups, \
+                      not to any write in between."
+    )]
+    Restore {
+        #[structopt(name = "DIR", parse(from_os_str))]
+        dir: PathBuf,
+
+        #[structopt(
+            long = "seq",
+            conflicts_with = "time",
+            help = "Restore up to and including the backup with this sequence number"
+        )]
+        seq: Option<u64>,
+
+        #[structopt(
+            long = "time",
+            help = "Restore up to the last backup taken at or before this Unix time"
+        )]
+        time: Option<u64>,
+    },
+
+    #[structopt(name = "dump", about = "Print the records of the log one by one")]
+    Dump {
+        #[structopt(long = "json", help = "Print each record as a JSON object")]
+        json: bool,
+    },
+
+    #[structopt(
+        name = "fsck",
+        about = "Check the log for corruption, the store must not be in use"
+    )]
+    Fsck {
+        #[structopt(long = "repair", help = "Rewrite the log keeping every intact record")]
+        repair: bool,
+    },
+
+    #[structopt(name = "stats", about = "Print statistics of the store")]
+    Stats {
+        #[structopt(long = "json", help = "Print the statistics as a JSON object")]
+        json: bool,
+    },
+
+    #[structopt(
+        name = "batch",
+        about = "Run commands read one per line from a file or stdin"
+    )]
+    Batch {
+        #[structopt(
+            long = "atomic",
+            help = "Apply every command or none of them, reporting results after the batch"
+        )]
+        atomic: bool,
+
+        #[structopt(
+            name = "FILE",
+            parse(from_os_str),
+            help = "File to read the commands from instead of stdin"
+        )]
+        file: Option<PathBuf>,
+    },
+
+    #[structopt(name = "shell", about = "Run commands interactively against the store")]
+    Shell,
+
+    #[structopt(name = "export", about = "Export every key-value pair")]
+    Export {
+        #[structopt(
+            long = "format",
+            default_value = "jsonl",
+            raw(possible_values = "&[\"jsonl\", \"csv\"]")
+        )]
+        format: DataFormat,
+
+        #[structopt(
+            name = "FILE",
+            parse(from_os_str),
+            help = "File to export to instead of stdout"
+        )]
+        file: Option<PathBuf>,
+    },
+
+    #[structopt(name = "import", about = "Import key-value pairs")]
+    Import {
+        #[structopt(
+            long = "format",
+            default_value = "jsonl",
+            raw(possible_values = "&[\"jsonl\", \"csv\"]")
+        )]
+        format: DataFormat,
+
+        #[structopt(long = "skip-existing", help = "Keep the value of keys already stored")]
+        skip_existing: bool,
+
+        #[structopt(
+            name = "FILE",
+            parse(from_os_str),
+            help = "File to import from instead of stdin"
+        )]
+        file: Option<PathBuf>,
+    },
+}
+
+/// Create the data directory `path` if missing
+///
+/// Return `Err` with a message for the user if `path` can't be used as the data directory
+fn prepare_dir(path: &Path) -> result::Result<(), String> {
+    if path.exists() && !path.is_dir() {
+        return Err(format!("{} is not a directory", path.display()));
+    }
+    fs::create_dir_all(path)
+        .map_err(|err| format!("can't create directory {}: {}", path.display(), err))
+}
+
+/// Find the output format requested in `args`, for errors found before they are parsed
+fn requested_output(args: &[OsString]) -> OutputFormat {
+    let mut output = OutputFormat::Text;
+    let mut args = args.iter().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        let value = match arg.strip_prefix("--output") {
+            Some("") => args.next(),
+            Some(value) => value.strip_prefix('=').map(|value| value.to_owned().into()),
+            None => None,
+        };
+        if let Some(format) = value.and_then(|value| value.parse().ok()) {
+            output = format;
+        }
+    }
+    output
+}
+
+fn main() {
+    let args: Vec<OsString> = env::args_os().collect();
+    let opt = match Opt::from_iter_safe(&args) {
+        Ok(opt) => opt,
+        Err(err) => {
+            // Help and version are printed through errors too
+            if err.use_stderr() {
+                match requested_output(&args) {
+                    OutputFormat::Text => eprintln!("{}", err.message),
+                    // Without the usage which follows the error, on one line
+                    OutputFormat::Json => {
+                        let message = err.message.split("\n\n").next().unwrap_or_default();
+                        let message = message.strip_prefix("error: ").unwrap_or(message);
+                        let message = message.split_whitespace().collect::<Vec<_>>().join(" ");
+                        print_error(&message, EXIT_USAGE, OutputFormat::Json);
+                    }
+                }
+                process::exit(EXIT_USAGE);
+            }
+            println!("{}", err.message);
+            process::exit(EXIT_SUCCESS);
+        }
+    };
+    if let Err(msg) = prepare_dir(&opt.dir) {
+        print_error(&msg, EXIT_USAGE, opt.output);
+        process::exit(EXIT_USAGE);
+    }
+
+    match run(opt.command, &opt.dir, opt.output) {
+        Ok(code) => process::exit(code),
+        Err(err) => {
+            let code = exit_code(&err);
+            print_error(&err.to_string(), code, opt.output);
+            process::exit(code);
+        }
+    }
+}
+
+/// Run `command` against the store in `path`, printing its result in `output`
+///
+/// Return `Ok(code)` the exit code if the command ran, even if it found a problem,
+/// return `Err` if the command failed
+fn run(command: Command, path: &Path, output: OutputFormat) -> Result<i32> {
+    let json = output == OutputFormat::Json;
+    match command {
+        Command::Set { key, value } => {
+            KvStore::open(path)?.set(key.clone(), value.clone())?;
+            if json {
+                print_json(&json!({ "key": key, "value": value }))?;
+            }
+        }
+        Command::Get { key } => match KvStore::open(path)?.get(key.clone())? {
+            Some(value) => match output {
+                OutputFormat::Text => print!("{}", value),
+                OutputFormat::Json => print_json(&json!({ "key": key, "value": value }))?,
+            },
+            None => return Err(kvs::Error::KeyNotFound(key)),
+        },
+        Command::Remove { key } => {
+            KvStore::open(path)?.remove(key.clone())?;
+            if json {
+                print_json(&json!({ "key": key }))?;
+            }
+        }
+        Command::Backup { dir, incremental } => {
+            let kvs = KvStore::open(path)?;
+            let seq = if incremental {
+                kvs.backup_incremental(&dir)?
+            } else {
+                kvs.backup(&dir)?;
+                1
+            };
+            if json {
+                print_json(&json!({ "seq": seq, "incremental": incremental }))?;
+            }
+        }
+        Command::Backups { dir } => {
+            let backups = KvStore::list_backups(&dir)?;
+            if json {
+                print_json(&json!({ "backups": backups }))?;
+            } else {
+                for backup in backups {
+                    let kind = if backup.incremental {
+                        "incremental"
+                    } else {
+                        "full"
+                    };
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        backup.seq, backup.created_at, kind, backup.records
+                    );
+                }
+            }
+        }
+        Command::Restore { dir, seq, time } => {
+            let point = match (seq, time) {
+                (Some(seq), _) => RestorePoint::Seq(seq),
+                (None, Some(time)) => RestorePoint::Time(time),
+                (None, None) => RestorePoint::Latest,
+            };
+            KvStore::restore_until(&dir, path, point)?;
+            if json {
+                print_json(&json!({ "restored": true }))?;
+            }
+        }
+        Command::Export { format, file } => {
+            let kvs = KvStore::open(path)?;
+            match file {
+                Some(file) => {
+                    let out = File::create(&file).map_err(|err| kvs::Error::from(err).at(&file))?;
+                    let count = kvs.export(out, format).map_err(|err| err.at(&file))?;
+                    if json {
+                        print_json(&json!({ "exported": count }))?;
+                    }
+                }
+                // The exported data is the output
+                None => {
+                    kvs.export(io::stdout(), format)?;
+                }
+            }
+        }
+        Command::Import {
+            format,
+            skip_existing,
+            file,
+        } => {
+            let mut kvs = KvStore::open(path)?;
+            let mode = if skip_existing {
+                ImportMode::SkipExisting
+            } else {
+                ImportMode::Overwrite
+            };
+            let summary = match file {
+                Some(file) => {
+                    let input = File::open(&file).map_err(|err| kvs::Error::from(err).at(&file))?;
+                    kvs.import(input, format, mode)?
+                }
+                None => kvs.import(io::stdin(), format, mode)?,
+            };
+            if json {
+                print_json(&summary)?;
+            }
+        }
+        Command::Dump { json: dump_json } => dump::run(path, json || dump_json)?,
+        Command::Fsck { repair } => {
+            let report = KvStore::fsck(path, repair)?;
+            if json {
+                print_json(&report)?;
+            } else {
+                println!("records: {}", report.records);
+                println!("live bytes: {}", report.live_bytes);
+                println!("stale bytes: {}", report.stale_bytes);
+                println!("corrupt regions: {}", report.corrupt_regions.len());
+                for region in report.corrupt_regions.iter() {
+                    println!("  offset {}, length {}", region.offset, region.len);
+                }
+                if report.legacy {
+                    println!("legacy format");
+                }
+                if report.repaired {
+                    println!("repaired");
+                }
+            }
+            if !report.is_clean() && !report.repaired {
+                return Ok(EXIT_CORRUPTION);
+            }
+        }
+        Command::Stats { json: stats_json } => {
+            let stats = KvStore::open(path)?.stats()?;
+            if json || stats_json {
+                print_json(&stats)?;
+            } else {
+                let last_compaction = stats
+                    .last_compaction
+                    .map_or("never".to_owned(), |time| time.to_string());
+                println!("live keys: {}", stats.live_keys);
+                println!("log bytes: {}", stats.log_bytes);
+                println!("stale bytes: {}", stats.stale_bytes);
+                println!("segments: {}", stats.segments);
+                println!("last compaction: {}", last_compaction);
+                println!("average key size: {:.1}", stats.avg_key_size);
+                println!("average value size: {:.1}", stats.avg_value_size);
+                println!("index memory: {}", stats.index_memory);
+            }
+        }
+        Command::Batch { atomic, file } => {
+            let mut kvs = KvStore::open(path)?;
+            return match file {
+                Some(file) => {
+                    let input = File::open(&file).map_err(|err| kvs::Error::from(err).at(&file))?;
+                    batch::run(&mut kvs, BufReader::new(input), atomic, output)
+                }
+                None => batch::run(&mut kvs, io::stdin().lock(), atomic, output),
+            };
+        }
+        Command::Shell => shell::run(&mut KvStore::open(path)?, output)?,
+    }
+    Ok(EXIT_SUCCESS)
+}
@@ -0,0 +1,94 @@
+//! Output formats and exit codes of the command line
+use serde::Serialize;
+use serde_json::json;
+use std::io::{self, Write};
+use std::result;
+use std::str::FromStr;
+
+/// The command succeeded
+pub const EXIT_SUCCESS: i32 = 0;
+/// A key doesn't exist
+pub const EXIT_NOT_FOUND: i32 = 1;
+/// The arguments or the input are invalid
+pub const EXIT_USAGE: i32 = 2;
+/// Reading or writing a file failed, or the store is in use
+pub const EXIT_IO: i32 = 3;
+/// The log or a backup is corrupted
+pub const EXIT_CORRUPTION: i32 = 4;
+
+/// Format of what the command line prints
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Plain text meant for people
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "unknown output format {}, expected text or json",
+                s
+            )),
+        }
+    }
+}
+
+/// Return the exit code for a command failing with `err`
+pub fn exit_code(err: &kvs::Error) -> i32 {
+    match err {
+        kvs::Error::KeyNotFound(_) => EXIT_NOT_FOUND,
+        kvs::Error::InvalidImport(_)
+        | kvs::Error::ValueTooLarge { .. }
+        | kvs::Error::BatchTooLarge { .. }
+        | kvs::Error::NotBackupDir(_)
+        | kvs::Error::NotStoreDir(_) => EXIT_USAGE,
+        kvs::Error::Io(_) | kvs::Error::File { .. } | kvs::Error::Locked(_) => EXIT_IO,
+        kvs::Error::Serde(_)
+        | kvs::Error::InvalidBackup(_)
+        | kvs::Error::BadBackupRecord { .. }
+        | kvs::Error::Corruption { .. }
+        | kvs::Error::UnsupportedVersion { .. }
+        | kvs::Error::Unrepairable(_) => EXIT_CORRUPTION,
+    }
+}
+
+/// Name of an exit code, as printed in JSON errors
+fn exit_code_name(code: i32) -> &'static str {
+    match code {
+        EXIT_SUCCESS => "success",
+        EXIT_NOT_FOUND => "not_found",
+        EXIT_USAGE => "usage",
+        EXIT_IO => "io",
+        EXIT_CORRUPTION => "corruption",
+        _ => "unknown",
+    }
+}
+
+/// Print `value` to stdout as one line of JSON
+pub fn print_json<T: Serialize>(value: &T) -> kvs::Result<()> {
+    let line = serde_json::to_string(value).map_err(io::Error::from)?;
+    writeln!(io::stdout(), "{}", line)?;
+    Ok(())
+}
+
+/// Print the error `message` to stderr, along with its exit `code` as JSON
+pub fn print_error(message: &str, code: i32, output: OutputFormat) {
+    match output {
+        OutputFormat::Text => eprintln!("kvs: {}", message),
+        OutputFormat::Json => {
+            let line = json!({
+                "error": message,
+                "kind": exit_code_name(code),
+                "code": code,
+            });
+            eprintln!("{}", line);
+        }
+    }
+}
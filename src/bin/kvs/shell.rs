@@ -0,0 +1,134 @@
+//! Interactive shell over one open store
+use crate::line::{split_words, LineCommand, COMMAND_NAMES, USAGE};
+use crate::output::{exit_code, print_error, OutputFormat, EXIT_USAGE};
+use kvs::{self, KvStore};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::env;
+use std::io;
+use std::path::PathBuf;
+
+/// Prompt shown before each line
+const PROMPT: &str = "kvs> ";
+
+/// History file's name in the home directory
+const HISTORY_FILE_NAME: &str = ".kvs_history";
+
+/// Words understood by the shell besides the commands
+const SHELL_WORDS: &[&str] = &["help", "exit"];
+
+/// Usage of the words understood by the shell besides the commands
+const SHELL_USAGE: &str = "help               Show this message
+exit               Leave the shell";
+
+/// Completes the command name at the start of the line
+struct ShellHelper;
+
+impl Completer for ShellHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        let word = before.trim_start();
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = COMMAND_NAMES
+            .iter()
+            .chain(SHELL_WORDS.iter())
+            .filter(|name| name.starts_with(word))
+            .map(|name| (*name).to_owned())
+            .collect();
+        Ok((before.len() - word.len(), candidates))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+/// Path of the history file, if the home directory is known
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE_NAME))
+}
+
+/// Convert a line editor error into the store's error type
+fn readline_error(err: ReadlineError) -> kvs::Error {
+    match err {
+        ReadlineError::Io(err) => kvs::Error::Io(err),
+        err => kvs::Error::Io(io::Error::other(err)),
+    }
+}
+
+/// Read commands from the user and run them against `store` until `exit` or end of input
+///
+/// Results are printed in `output`. Errors of a single command are printed and the shell
+/// goes on.
+pub fn run(store: &mut KvStore, output: OutputFormat) -> kvs::Result<()> {
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new().map_err(readline_error)?;
+    editor.set_helper(Some(ShellHelper));
+    let history_path = history_path();
+    if let Some(path) = &history_path {
+        // A missing history file is expected on first use
+        let _ = editor.load_history(path);
+    }
+
+    loop {
+        let line = match editor.readline(PROMPT) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(readline_error(err)),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        editor
+            .add_history_entry(line.as_str())
+            .map_err(readline_error)?;
+
+        let words = match split_words(&line) {
+            Ok(words) => words,
+            Err(msg) => {
+                print_error(&msg, EXIT_USAGE, output);
+                continue;
+            }
+        };
+        match words[0].as_str() {
+            "exit" => break,
+            "help" => {
+                println!("{}\n{}", USAGE, SHELL_USAGE);
+                continue;
+            }
+            _ => {}
+        }
+        match LineCommand::parse(&words).map(|command| command.execute(store, output)) {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => print_error(&err.to_string(), exit_code(&err), output),
+            Err(msg) => print_error(&msg, EXIT_USAGE, output),
+        }
+    }
+
+    if let Some(path) = &history_path {
+        if let Err(err) = editor.save_history(path) {
+            eprintln!("warning: can't save history to {}: {}", path.display(), err);
+        }
+    }
+    Ok(())
+}
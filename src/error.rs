@@ -0,0 +1,178 @@
+//! Error type of the KV store
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::result;
+
+/// Custom error type
+#[derive(Debug)]
+pub enum Error {
+    /// IO Error
+    Io(io::Error),
+    /// IO error on a file of the store
+    File {
+        /// Path of the file
+        path: PathBuf,
+        /// Underlying IO error
+        source: io::Error,
+    },
+    /// Serde error
+    Serde(bincode::Error),
+    /// Key not found error
+    KeyNotFound(String),
+    /// Backup is incomplete or inconsistent
+    InvalidBackup(String),
+    /// Record of a backup data file is truncated or can't be deserialized
+    BadBackupRecord {
+        /// Path of the backup data file
+        path: PathBuf,
+        /// Offset of the bad record
+        offset: u64,
+        /// Underlying error
+        source: Box<Error>,
+    },
+    /// Imported data is malformed
+    InvalidImport(String),
+    /// Record of the log is corrupted, truncated or can't be deserialized
+    Corruption {
+        /// Path of the log data file
+        path: PathBuf,
+        /// Offset of the bad record
+        offset: u64,
+    },
+    /// Serialized command is longer than a record can hold, because of its value or its key
+    ValueTooLarge {
+        /// Key of the command
+        key: String,
+        /// Length of the serialized command in bytes
+        len: usize,
+        /// Maximum length of a serialized command in bytes
+        max: usize,
+    },
+    /// Serialized batch is longer than a record can hold
+    BatchTooLarge {
+        /// Length of the serialized batch in bytes
+        len: usize,
+        /// Maximum length of a serialized batch in bytes
+        max: usize,
+    },
+    /// Directory is locked by another open store
+    Locked(PathBuf),
+    /// Backup destination holds a store's data instead of backups
+    NotBackupDir(PathBuf),
+    /// Restore destination holds backups instead of a store's data
+    NotStoreDir(PathBuf),
+    /// Log data file was written in an unknown version of the format
+    UnsupportedVersion {
+        /// Path of the log data file
+        path: PathBuf,
+        /// Version found in the header
+        version: u16,
+    },
+    /// Log data file holds no intact record to repair it with
+    Unrepairable(PathBuf),
+}
+
+impl Error {
+    /// Attach the `path` of the file an IO error happened on, other errors are unchanged
+    pub fn at(self, path: &Path) -> Error {
+        match self {
+            Error::Io(source) => Error::File {
+                path: path.to_owned(),
+                source,
+            },
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "IO error: {}", err),
+            Error::File { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Serde(err) => write!(f, "serialization error: {}", err),
+            Error::KeyNotFound(key) => write!(f, "key not found: {}", key),
+            Error::InvalidBackup(msg) => write!(f, "invalid backup: {}", msg),
+            Error::BadBackupRecord {
+                path,
+                offset,
+                source,
+            } => write!(
+                f,
+                "invalid backup: {}: bad record at offset {}: {}",
+                path.display(),
+                offset,
+                source
+            ),
+            Error::InvalidImport(msg) => write!(f, "invalid import: {}", msg),
+            Error::Corruption { path, offset } => {
+                write!(
+                    f,
+                    "{}: bad record at offset {}, run `kvs fsck --repair` to drop the bad records",
+                    path.display(),
+                    offset
+                )
+            }
+            Error::ValueTooLarge { key, len, max } => write!(
+                f,
+                "record for key {} is too large: {} bytes serialized, at most {}",
+                key, len, max
+            ),
+            Error::BatchTooLarge { len, max } => write!(
+                f,
+                "batch is too large: {} bytes serialized, at most {}",
+                len, max
+            ),
+            Error::Locked(path) => {
+                write!(f, "{} is locked by another open store", path.display())
+            }
+            Error::NotBackupDir(path) => write!(
+                f,
+                "{} is a store's data directory, not a backup directory",
+                path.display()
+            ),
+            Error::NotStoreDir(path) => write!(
+                f,
+                "{} is a backup directory, not a store's data directory",
+                path.display()
+            ),
+            Error::UnsupportedVersion { path, version } => {
+                write!(f, "{}: unsupported log version {}", path.display(), version)
+            }
+            Error::Unrepairable(path) => write!(
+                f,
+                "{}: no intact record found, refusing to replace the log",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::File { source, .. } => Some(source),
+            Error::Serde(err) => Some(err),
+            Error::BadBackupRecord { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(err: bincode::Error) -> Error {
+        Error::Serde(err)
+    }
+}
+
+/// Custom result type
+pub type Result<T> = result::Result<T, Error>;
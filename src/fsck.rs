@@ -0,0 +1,149 @@
+//! Offline integrity check and repair of the log
+use super::record::{
+    decode_legacy_record, decode_record, log_format, write_log_file, LogFormat, LOG_HEADER_LEN,
+};
+use super::{lock_dir, Command, Error, KvStore, Result, LOG_DATA_FILE_NAME};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+/// Temporary log data file's name used while repairing
+const REPAIR_TMP_FILE_NAME: &str = "log.data.repair";
+
+/// Region of the log holding no intact record
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CorruptRegion {
+    /// Offset of the region in the log
+    pub offset: u64,
+    /// Length of the region in bytes
+    pub len: u64,
+}
+
+/// Result of checking the log
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct FsckReport {
+    /// Number of intact records
+    pub records: u64,
+    /// Bytes of the intact records holding the current data
+    pub live_bytes: u64,
+    /// Bytes of the intact records superseded by later ones
+    pub stale_bytes: u64,
+    /// Regions holding no intact record, in log order
+    pub corrupt_regions: Vec<CorruptRegion>,
+    /// Whether the log is in the format written before the header and the checksums were
+    /// introduced, which opening the store or repairing upgrades
+    pub legacy: bool,
+    /// Whether the log was rewritten without its corrupt regions, in the current format
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    /// Whether no corrupt region was found
+    pub fn is_clean(&self) -> bool {
+        self.corrupt_regions.is_empty()
+    }
+}
+
+impl KvStore {
+    /// Check the log data file of the KV store in `path`
+    ///
+    /// Every record's framing and checksum is validated. Unlike `KvStore::open`, which refuses
+    /// a log with a bad record, the check skips past a damaged region by looking for
+    /// the next offset where an intact record starts. A legacy log has no checksums to tell
+    /// where that is, so everything after its first bad record is one corrupt region.
+    ///
+    /// If `repair` is set and corrupt regions were found or the log is a legacy one, the log is
+    /// atomically replaced by one holding every intact record, in order, in the current format.
+    /// A log holding no intact record at all is left as is.
+    ///
+    /// Return `Ok(report)` if success,
+    /// return `Err(Error::Locked)` if a store has the directory `path` open,
+    /// return `Err(Error::UnsupportedVersion)` if the log was written in an unknown format,
+    /// return `Err(Error::Unrepairable)` if repairing a log holding no intact record,
+    /// return `Err` when other error occurs
+    pub fn fsck(path: &Path, repair: bool) -> Result<FsckReport> {
+        let _lock = lock_dir(path)?;
+        let log_path = path.join(LOG_DATA_FILE_NAME);
+        let buf = fs::read(&log_path).map_err(|err| Error::from(err).at(&log_path))?;
+        let format = log_format(&buf, &log_path)?;
+        let legacy = format == LogFormat::Legacy;
+        let decode = if legacy {
+            decode_legacy_record
+        } else {
+            decode_record
+        };
+
+        let mut report = FsckReport {
+            legacy,
+            ..FsckReport::default()
+        };
+        let mut records: Vec<(Command, usize)> = Vec::new();
+        let mut live: HashMap<String, usize> = HashMap::new();
+        let mut offset = match format {
+            LogFormat::Current => LOG_HEADER_LEN as usize,
+            LogFormat::Empty | LogFormat::Legacy | LogFormat::Unknown => 0,
+        };
+        let mut corrupt_start: Option<usize> = None;
+        while offset < buf.len() {
+            let (command, len) = match decode(&buf[offset..]) {
+                Some(record) => record,
+                None if legacy => {
+                    corrupt_start = Some(offset);
+                    break;
+                }
+                None => {
+                    corrupt_start.get_or_insert(offset);
+                    offset += 1;
+                    continue;
+                }
+            };
+            if let Some(start) = corrupt_start.take() {
+                report.corrupt_regions.push(CorruptRegion {
+                    offset: start as u64,
+                    len: (offset - start) as u64,
+                });
+            }
+            for command in command.clone().into_commands() {
+                match command {
+                    Command::Set { key, .. } => {
+                        live.insert(key, records.len());
+                    }
+                    Command::Remove { key } => {
+                        live.remove(&key);
+                    }
+                    Command::Get { .. } | Command::Batch { .. } => {}
+                }
+            }
+            records.push((command, len as usize));
+            offset += len as usize;
+        }
+        if let Some(start) = corrupt_start {
+            report.corrupt_regions.push(CorruptRegion {
+                offset: start as u64,
+                len: (buf.len() - start) as u64,
+            });
+        }
+
+        report.records = records.len() as u64;
+        // The keys set by one batch share its record
+        let live_records: HashSet<usize> = live.values().cloned().collect();
+        report.live_bytes = live_records
+            .iter()
+            .map(|&index| records[index].1 as u64)
+            .sum();
+        report.stale_bytes =
+            records.iter().map(|&(_, len)| len as u64).sum::<u64>() - report.live_bytes;
+
+        if repair && (!report.is_clean() || legacy) {
+            if records.is_empty() && buf.len() as u64 > LOG_HEADER_LEN {
+                return Err(Error::Unrepairable(log_path));
+            }
+            let commands: Vec<Command> = records.into_iter().map(|(command, _)| command).collect();
+            write_log_file(path, REPAIR_TMP_FILE_NAME, &commands)?;
+            report.repaired = true;
+        }
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,166 @@
+//! Import and export of the KV store's live data in text formats
+use super::record::split_batch;
+use super::{Command, Error, KvStore, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::result;
+use std::str::FromStr;
+
+/// Format of exported and imported data
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataFormat {
+    /// One `{"key": ..., "value": ...}` JSON object per line
+    JsonLines,
+    /// CSV with a `key,value` header
+    Csv,
+}
+
+impl FromStr for DataFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(DataFormat::JsonLines),
+            "csv" => Ok(DataFormat::Csv),
+            _ => Err(format!("unknown format {}, expected jsonl or csv", s)),
+        }
+    }
+}
+
+/// What to do with imported keys which already exist in the store
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImportMode {
+    /// Replace the stored value
+    Overwrite,
+    /// Keep the stored value
+    SkipExisting,
+}
+
+/// Counts of keys handled by an import
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct ImportSummary {
+    /// Number of keys written
+    pub imported: u64,
+    /// Number of keys skipped because they already existed
+    pub skipped: u64,
+}
+
+/// One key-value pair, as exported and imported
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: String,
+}
+
+impl KvStore {
+    /// Export every live key-value pair to `writer` in `format`, ordered by key
+    ///
+    /// Return `Ok(count)` the number of pairs exported if success,
+    /// return `Err` if failure
+    pub fn export<W: Write>(&self, writer: W, format: DataFormat) -> Result<u64> {
+        let mut keys: Vec<&String> = self.key_value_map.keys().collect();
+        keys.sort();
+
+        let mut writer = BufWriter::new(writer);
+        match format {
+            DataFormat::JsonLines => {
+                for key in keys.iter() {
+                    let record = Record {
+                        key: (*key).clone(),
+                        value: self.key_value_map[*key].clone(),
+                    };
+                    let line = serde_json::to_string(&record).map_err(io::Error::from)?;
+                    writeln!(writer, "{}", line)?;
+                }
+            }
+            DataFormat::Csv => {
+                let mut csv_writer = csv::Writer::from_writer(&mut writer);
+                for key in keys.iter() {
+                    csv_writer
+                        .serialize(Record {
+                            key: (*key).clone(),
+                            value: self.key_value_map[*key].clone(),
+                        })
+                        .map_err(io::Error::from)?;
+                }
+                csv_writer.flush()?;
+            }
+        }
+        writer.flush()?;
+
+        Ok(keys.len() as u64)
+    }
+
+    /// Import key-value pairs in `format` from `reader`
+    ///
+    /// The whole input is parsed before anything is written, so malformed input leaves the
+    /// store untouched. The pairs are then written as by `KvStore::apply_batch`, in as many
+    /// batches as needed for each to fit in one log record, and the log is compacted once at
+    /// the end; if writing fails partway, the batches written before stay applied.
+    ///
+    /// Return `Ok(summary)` if success,
+    /// return `Err(Error::InvalidImport)` if the input is malformed,
+    /// return `Err` when other error occurs
+    pub fn import<R: Read>(
+        &mut self,
+        reader: R,
+        format: DataFormat,
+        mode: ImportMode,
+    ) -> Result<ImportSummary> {
+        let records = match format {
+            DataFormat::JsonLines => read_json_lines(reader)?,
+            DataFormat::Csv => read_csv(reader)?,
+        };
+
+        let mut summary = ImportSummary::default();
+        let mut commands = Vec::new();
+        for Record { key, value } in records {
+            if mode == ImportMode::SkipExisting && self.key_value_map.contains_key(&key) {
+                summary.skipped += 1;
+                continue;
+            }
+            commands.push(Command::Set { key, value });
+            summary.imported += 1;
+        }
+        let batches = split_batch(commands)?;
+        for batch in batches.iter() {
+            self.append_batch(batch)?;
+        }
+        if !batches.is_empty() {
+            self.compact_log_file()?;
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Parse JSON Lines records, ignoring blank lines
+fn read_json_lines<R: Read>(reader: R) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (index, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .map_err(|err| Error::InvalidImport(format!("line {}: {}", index + 1, err)))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Parse CSV records with a `key,value` header
+fn read_csv<R: Read>(reader: R) -> Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for record in csv::Reader::from_reader(reader).deserialize() {
+        let record = record.map_err(|err| {
+            if err.is_io_error() {
+                Error::Io(io::Error::from(err))
+            } else {
+                Error::InvalidImport(err.to_string())
+            }
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
@@ -3,12 +3,30 @@
 extern crate libc;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::File;
-use std::fs::OpenOptions;
+use std::fs::{self, File, OpenOptions, TryLockError};
 use std::io::{self, prelude::*};
-use std::path::Path;
-use std::result;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod backup;
+mod batch;
+mod error;
+mod fsck;
+mod import_export;
+mod record;
+mod stats;
+
+pub use backup::{BackupInfo, RestorePoint};
+pub use error::{Error, Result};
+pub use fsck::{CorruptRegion, FsckReport};
+pub use import_export::{DataFormat, ImportMode, ImportSummary};
+pub use record::{LogReader, LogRecord};
+pub use stats::Stats;
+
+use record::{
+    probe_log_format, read_command, upgrade_legacy_log, write_command, write_log_file,
+    write_log_header, LogFormat,
+};
 
 /// Key value store struct
 #[derive(Debug)]
@@ -18,78 +36,158 @@ pub struct KvStore {
     /// <key>-<value> map
     key_value_map: HashMap<String, String>,
     log_file: File,
+    /// Exclusive lock of the directory, released when the store is dropped
+    _lock: File,
+    /// Directory holding the log data file
+    path: PathBuf,
+    /// Seconds since the Unix epoch of the last compaction, if any
+    ///
+    /// Saved in the `last_compaction` file on a best-effort basis, and read back on open.
+    last_compaction: Option<u64>,
 }
 
-/// Custom error type
-#[derive(Debug)]
-pub enum Error {
-    /// IO Error
-    Io(io::Error),
-    /// Serde error
-    Serde(bincode::Error),
-    /// Key not found error
-    KeyNotFound(String),
-}
+/// Command recorded in the log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    /// Set the value of `key` to `value`
+    Set {
+        /// Key
+        key: String,
+        /// Value
+        value: String,
+    },
 
-impl From<io::Error> for Error {
-    fn from(err: io::Error) -> Error {
-        Error::Io(err)
-    }
+    /// Get the value of `key`, never written by the store
+    Get {
+        /// Key
+        key: String,
+    },
+
+    /// Remove `key`
+    Remove {
+        /// Key
+        key: String,
+    },
+
+    /// Apply `commands` in order, all of them or none, as written by `KvStore::apply_batch`
+    Batch {
+        /// Commands of the batch
+        commands: Vec<Command>,
+    },
 }
 
-impl From<bincode::Error> for Error {
-    fn from(err: bincode::Error) -> Error {
-        Error::Serde(err)
+impl Command {
+    /// Return the commands to replay for this one, those of a batch in order
+    pub(crate) fn into_commands(self) -> Vec<Command> {
+        match self {
+            Command::Batch { commands } => commands
+                .into_iter()
+                .flat_map(Command::into_commands)
+                .collect(),
+            command => vec![command],
+        }
     }
 }
 
-/// Custom result type
-pub type Result<T> = result::Result<T, Error>;
+/// Log data file's name
+const LOG_DATA_FILE_NAME: &str = "log.data";
+
+/// Temporary log data file's name used while compacting
+const COMPACTION_TMP_FILE_NAME: &str = "log.data.compact";
+
+/// Name of the file holding the time of the last compaction
+const LAST_COMPACTION_FILE_NAME: &str = "last_compaction";
 
-/// For serde
-#[derive(Debug, Serialize, Deserialize)]
-enum Command {
-    Set { key: String, value: String },
+/// Temporary name of the file holding the time of the last compaction, while it is replaced
+const LAST_COMPACTION_TMP_FILE_NAME: &str = "last_compaction.tmp";
 
-    Get { key: String },
+/// Name of the file locked by the store holding the directory
+const LOCK_FILE_NAME: &str = "LOCK";
 
-    Remove { key: String },
+/// Take the exclusive lock of the directory `path`, so no other store uses it meanwhile
+///
+/// Return the locked file, which releases the lock when dropped,
+/// return `Err(Error::Locked)` if the lock is held elsewhere,
+/// return `Err` when other error occurs
+fn lock_dir(path: &Path) -> Result<File> {
+    let lock_path = path.join(LOCK_FILE_NAME);
+    let lock_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .map_err(|err| Error::from(err).at(&lock_path))?;
+    match lock_file.try_lock() {
+        Ok(()) => Ok(lock_file),
+        Err(TryLockError::WouldBlock) => Err(Error::Locked(path.to_owned())),
+        Err(TryLockError::Error(err)) => Err(Error::from(err).at(&lock_path)),
+    }
 }
 
-/// Log data file's name
-const LOG_DATA_FILE_NAME: &str = "log.data";
+/// Record `time` as the last compaction of the store in `path`
+///
+/// The time is written to a temporary file renamed over the old one, so a crash leaves either
+/// time. Errors are ignored: the log already holds the compacted data, and only the time is lost.
+fn save_compaction_time(path: &Path, time: u64) {
+    let tmp_path = path.join(LAST_COMPACTION_TMP_FILE_NAME);
+    let _ = fs::write(&tmp_path, time.to_string())
+        .and_then(|_| fs::rename(&tmp_path, path.join(LAST_COMPACTION_FILE_NAME)));
+}
+
+/// Return the time of the last compaction recorded in `path`, if any
+fn load_compaction_time(path: &Path) -> Option<u64> {
+    fs::read_to_string(path.join(LAST_COMPACTION_FILE_NAME))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Seconds since the Unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
 
 /// Implementation choices
 /// 
 /// Questions:
 /// 
 /// 1. Serialization Format
-///     Do you want to prioritize performance? Do you want to be able to read the content of the
-///     log in plain text?
+///    Do you want to prioritize performance? Do you want to be able to read the content of the
+///    log in plain text?
 /// 
 /// 2. Serialization Method
-///     Write it either to a String or a stream implementing Write?
+///    Write it either to a String or a stream implementing Write?
 /// 
 /// 3. Deserialization Method
-///     3.1 Should you read all records in the log into memory at once and then replay them into 
-///         your map type; or should you read them one at a time while replaying the into your map?
-///     3.2 Should you read into a buffer before deserializing or deserialize from a file stream?
+///    3.1 Should you read all records in the log into memory at once and then replay them into 
+///    your map type; or should you read them one at a time while replaying the into your map?
+///    3.2 Should you read into a buffer before deserializing or deserialize from a file stream?
 ///
 /// 4. IO Mode
-///     Read and write the log data file in which IO mode? Buffered or direct? Block or non-block?
-///     Sync or async?
+///    Read and write the log data file in which IO mode? Buffered or direct? Block or non-block?
+///    Sync or async?
 /// 
 /// Answers:
 /// 
 /// A1: Bincode
 ///     Binary format:
 ///     ```
-///     <length of serialized command><serialized command><length of ...>...
+///     <magic><version><length of serialized command><checksum><serialized command><length of ...>...
 ///     ```
+///     The log starts with the magic bytes "KVSLOG" and the format version in 2Bytes (big
+///     endian), so a log written in another format is recognized instead of misread. Logs
+///     written before the header existed are upgraded when opened.
 ///     To meet the requirements that the key's maximum size is 256B and the value's maximum size
 ///     is 4KB while the serialized command's maximum size is no less than (4096 + 256 = 4352)B,
 ///     the size of bytes to represent the size of serialized command is set as 2Bytes (big endian),
 ///     which support max size of (2^16 - 1 = 65535)B.
+///     The checksum is the CRC32 of the serialized command in 4Bytes (big endian), so corrupted
+///     records are detected rather than misread, and `KvStore::fsck` can find the next intact
+///     record after a damaged one.
 /// 
 /// A2: Write it to a String
 /// 
@@ -137,49 +235,105 @@ impl KvStore {
     /// the application buffer. This “doublecopying” of data results in more CPU
     /// consumption and adds overhead to the memory too.
     ///
-    /// Return the new instance
+    /// A record cut off at the end of the log, as left by a crash while appending it, is dropped
+    /// with a warning on stderr, as if it was never written.
+    ///
+    /// Return the new instance,
+    /// return `Err(Error::Locked)` if another store has the directory `path` open,
+    /// return `Err(Error::Corruption)` if a record of the log is bad, see `KvStore::fsck`,
+    /// return `Err(Error::UnsupportedVersion)` if the log was written in an unknown format,
+    /// return `Err` when other error occurs
     pub fn open(path: &Path) -> Result<Self> {
         let mut key_offset_map: HashMap<String, u64> = HashMap::new();
         let mut key_value_map: HashMap<String, String> = HashMap::new();
 
+        let lock = lock_dir(path)?;
+        let log_path = path.join(LOG_DATA_FILE_NAME);
         let mut options = OpenOptions::new();
         options.read(true).write(true).create(true);
-        let mut log_file = options.open(path.join(LOG_DATA_FILE_NAME))?;
-
-        let mut log_offset: u64 = 0;
-        loop {
-            let mut length_bytes_buf: [u16; 1] = [0; 1];
-            let result = log_file
-                .read_u16_into::<BigEndian>(&mut length_bytes_buf)
-                .map_err(Error::Io)
-                .and_then(|_| {
-                    let len = length_bytes_buf.first().unwrap();
-                    let mut command_buf = vec![0; *len as usize];
-                    log_file.read_exact(&mut command_buf)?;
-                    let command: Command = bincode::deserialize(&command_buf)?;
-                    match command {
-                        Command::Set { key, value } => {
-                            key_offset_map.insert(key.clone(), log_offset);
-                            key_value_map.insert(key, value);
-                        }
-                        Command::Remove { key } => {
-                            key_offset_map.remove(&key);
-                            key_value_map.remove(&key);
-                        }
-                        Command::Get { .. } => {}
-                    }
-                    log_offset += u64::from(*len) + 2;
-                    Ok(())
+        let mut log_file = options
+            .open(&log_path)
+            .map_err(|err| Error::from(err).at(&log_path))?;
+        match probe_log_format(&mut log_file, &log_path)? {
+            LogFormat::Current => {}
+            LogFormat::Empty => {
+                write_log_header(&mut log_file).map_err(|err| err.at(&log_path))?;
+                log_file
+                    .seek(io::SeekFrom::Start(0))
+                    .map_err(|err| Error::from(err).at(&log_path))?;
+            }
+            LogFormat::Legacy => {
+                upgrade_legacy_log(path)?;
+                log_file = options
+                    .open(&log_path)
+                    .map_err(|err| Error::from(err).at(&log_path))?;
+            }
+            LogFormat::Unknown => {
+                return Err(Error::Corruption {
+                    path: log_path,
+                    offset: 0,
                 });
-            if result.is_err() {
-                break;
             }
         }
 
+        let mut torn_offset = None;
+        let mut reader = LogReader::new(&mut log_file);
+        while let Some(record) = reader.next() {
+            let (command, log_offset) = match record {
+                Ok(LogRecord {
+                    command: Some(command),
+                    offset,
+                    ..
+                }) => (command, offset),
+                Ok(LogRecord { offset, .. }) => {
+                    return Err(Error::Corruption {
+                        path: log_path,
+                        offset,
+                    });
+                }
+                // A record cut off at the end, as a crash while appending leaves it
+                Err(Error::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    torn_offset = Some(reader.offset());
+                    break;
+                }
+                Err(err) => return Err(err.at(&log_path)),
+            };
+            for command in command.into_commands() {
+                match command {
+                    Command::Set { key, value } => {
+                        key_offset_map.insert(key.clone(), log_offset);
+                        key_value_map.insert(key, value);
+                    }
+                    Command::Remove { key } => {
+                        key_offset_map.remove(&key);
+                        key_value_map.remove(&key);
+                    }
+                    Command::Get { .. } | Command::Batch { .. } => {}
+                }
+            }
+        }
+
+        if let Some(offset) = torn_offset {
+            eprintln!(
+                "warning: {}: dropping the record cut off at offset {}",
+                log_path.display(),
+                offset
+            );
+            log_file
+                .set_len(offset)
+                .and_then(|_| log_file.sync_all())
+                .map_err(|err| Error::from(err).at(&log_path))?;
+        }
+
+        let last_compaction = load_compaction_time(path);
+
         Ok(KvStore {
             key_offset_map,
             key_value_map,
             log_file,
+            _lock: lock,
+            path: path.to_owned(),
+            last_compaction,
         })
     }
 
@@ -191,21 +345,19 @@ impl KvStore {
     ///     If that succeeds, it exits silently with error code 0
     ///     If it fails, it exits by printing the error and returning a non-zero error code
     ///
-    /// Binary format:
-    ///     <length of serialized command><serialized command>
+    /// Binary format, see `record.rs`:
+    ///     <magic><version> once at the start of the log, then for each command
+    ///     <length of serialized command><CRC32 of serialized command><serialized command>
     ///
     /// Return `Ok` if success,
-    /// return `Err` if failure
+    /// return `Err(Error::ValueTooLarge)` if the serialized command doesn't fit in a record,
+    /// return `Err` when other error occurs
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
         let command = Command::Set {
             key: key.clone(),
             value: value.clone(),
         };
-        let encoded: Vec<u8> = bincode::serialize(&command).unwrap();
-        let current_log_file_offset = self.log_file.seek(io::SeekFrom::End(0))?;
-        self.log_file.write_u16::<BigEndian>(encoded.len() as u16)?;
-        self.log_file.write_all(&encoded)?;
-        self.log_file.flush()?;
+        let current_log_file_offset = self.append(&command)?;
         self.key_offset_map.insert(key.to_owned(), current_log_file_offset);
         self.key_value_map.insert(key.to_owned(), value);
         self.compact_log_file()?;
@@ -262,11 +414,7 @@ impl KvStore {
     /// return `Err` when other error occurs
     pub fn remove(&mut self, key: String) -> Result<String> {
         let command = Command::Remove { key: key.clone() };
-        let encoded: Vec<u8> = bincode::serialize(&command).unwrap();
-        self.log_file.seek(io::SeekFrom::End(0))?;
-        self.log_file.write_u16::<BigEndian>(encoded.len() as u16)?;
-        self.log_file.write_all(&encoded)?;
-        self.log_file.flush()?;
+        self.append(&command)?;
 
         let stored_value = self.get(key.clone());
         self.key_offset_map.remove(&key);
@@ -279,26 +427,69 @@ impl KvStore {
         }
     }
 
+    /// Scan the live key-value pairs whose key starts with `prefix`
+    ///
+    /// Return the pairs ordered by key
+    pub fn scan(&self, prefix: &str) -> Vec<(String, String)> {
+        let mut pairs: Vec<(String, String)> = self
+            .key_value_map
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        pairs.sort();
+        pairs
+    }
+
     /// Compact the log 
     /// 
     /// Naive solution: similar like the map initialization while opening the log file.
     /// Steps:
     /// 2. Write it back to the log file
     /// 
+    /// The compacted log is written to a temporary file, synced and renamed over the log, so a
+    /// crash leaves either the old log or the new one, never a partial rewrite.
+    ///
     /// When to compact?
     fn compact_log_file(&mut self) -> Result<()> {
+        let log_path = self.path.join(LOG_DATA_FILE_NAME);
 
         // 2. Write it back to the log file
-        self.log_file.set_len(0)?;
-        for (key, value) in self.key_value_map.iter() {
-            let command = Command::Set { key: key.clone(), value: value.clone() };
-            let encoded: Vec<u8> = bincode::serialize(&command).unwrap();
-            self.log_file.seek(io::SeekFrom::End(0))?;
-            self.log_file.write_u16::<BigEndian>(encoded.len() as u16)?;
-            self.log_file.write_all(&encoded)?;
-        }
-        self.log_file.flush()?;
+        let commands: Vec<Command> = self
+            .key_value_map
+            .iter()
+            .map(|(key, value)| Command::Set {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        write_log_file(&self.path, COMPACTION_TMP_FILE_NAME, &commands)?;
+        self.log_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&log_path)
+            .map_err(|err| Error::from(err).at(&log_path))?;
+
+        let time = now();
+        save_compaction_time(&self.path, time);
+        self.last_compaction = Some(time);
 
         Ok(())
     }
+
+    /// Append `command` to the log
+    ///
+    /// Return the offset of the new record
+    fn append(&mut self, command: &Command) -> Result<u64> {
+        let log_path = self.path.join(LOG_DATA_FILE_NAME);
+        let offset = self
+            .log_file
+            .seek(io::SeekFrom::End(0))
+            .map_err(|err| Error::from(err).at(&log_path))?;
+        write_command(&mut self.log_file, command).map_err(|err| err.at(&log_path))?;
+        self.log_file
+            .flush()
+            .map_err(|err| Error::from(err).at(&log_path))?;
+        Ok(offset)
+    }
 }
\ No newline at end of file
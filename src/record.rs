@@ -0,0 +1,430 @@
+//! Framing of the records in the log
+//!
+//! Binary format:
+//!     <magic><version><length of serialized command><checksum><serialized command><length of ...>...
+//!
+//! Logs written before the header and the checksums were introduced hold
+//!     <length of serialized command><serialized command><length of ...>...
+//! and are upgraded to the current format when the store opens them.
+use super::{Command, Error, Result, LOG_DATA_FILE_NAME};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, WriteBytesExt};
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::path::Path;
+
+/// Magic bytes starting a log data file
+const LOG_MAGIC: &[u8] = b"KVSLOG";
+
+/// Version of the record format, written after the magic bytes
+const LOG_VERSION: u16 = 1;
+
+/// Length of the magic bytes and the version starting a log data file
+pub(crate) const LOG_HEADER_LEN: u64 = 8;
+
+/// Length of the length and checksum in front of each serialized command
+pub(crate) const RECORD_HEADER_LEN: u64 = 6;
+
+/// Length of the legacy framing in front of each serialized command
+const LEGACY_RECORD_HEADER_LEN: u64 = 2;
+
+/// Number of variants of `Command`, bincode serializes the variant first as a `u32`
+const COMMAND_VARIANTS: u32 = 4;
+
+/// Temporary log data file's name used while upgrading a legacy log
+const UPGRADE_TMP_FILE_NAME: &str = "log.data.upgrade";
+
+/// Format of a log data file, as told by its first bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum LogFormat {
+    /// Nothing written yet
+    Empty,
+    /// Header and checksummed records
+    Current,
+    /// Records written before the header and the checksums were introduced
+    Legacy,
+    /// Neither, the header is damaged
+    Unknown,
+}
+
+/// Write the header starting a log data file
+pub(crate) fn write_log_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(LOG_MAGIC)?;
+    writer.write_u16::<BigEndian>(LOG_VERSION)?;
+    Ok(())
+}
+
+/// Tell the format of the log data file starting with `buf`
+///
+/// A log which doesn't start with the header is only taken for a legacy one if its first
+/// record decodes in the legacy framing, otherwise its header is damaged.
+///
+/// Return `Ok(format)` if success,
+/// return `Err(Error::UnsupportedVersion)` if the header holds another version
+pub(crate) fn log_format(buf: &[u8], log_path: &Path) -> Result<LogFormat> {
+    if buf.is_empty() {
+        return Ok(LogFormat::Empty);
+    }
+    if buf.len() as u64 >= LOG_HEADER_LEN && buf.starts_with(LOG_MAGIC) {
+        let version = BigEndian::read_u16(&buf[LOG_MAGIC.len()..LOG_HEADER_LEN as usize]);
+        if version != LOG_VERSION {
+            return Err(Error::UnsupportedVersion {
+                path: log_path.to_owned(),
+                version,
+            });
+        }
+        return Ok(LogFormat::Current);
+    }
+    match decode_legacy_record(buf) {
+        Some(_) => Ok(LogFormat::Legacy),
+        None => Ok(LogFormat::Unknown),
+    }
+}
+
+/// Tell the format of the log data file `file` in `log_path` from its first bytes
+///
+/// `file` is left at its start.
+pub(crate) fn probe_log_format(file: &mut File, log_path: &Path) -> Result<LogFormat> {
+    let mut buf = Vec::new();
+    read_prefix(file, &mut buf).map_err(|err| Error::from(err).at(log_path))?;
+    log_format(&buf, log_path)
+}
+
+/// Read the bytes of `file` which may hold the header or the first legacy record into `buf`
+fn read_prefix(file: &mut File, buf: &mut Vec<u8>) -> io::Result<()> {
+    file.seek(io::SeekFrom::Start(0))?;
+    file.take(LEGACY_RECORD_HEADER_LEN + MAX_COMMAND_LEN as u64)
+        .read_to_end(buf)?;
+    file.seek(io::SeekFrom::Start(0))?;
+    Ok(())
+}
+
+/// Decode the legacy record at the start of `buf`
+///
+/// Return `Some((command, length))` if `buf` starts with a whole record which can be
+/// deserialized, return `None` otherwise
+pub(crate) fn decode_legacy_record(buf: &[u8]) -> Option<(Command, u64)> {
+    if (buf.len() as u64) < LEGACY_RECORD_HEADER_LEN {
+        return None;
+    }
+    let end = LEGACY_RECORD_HEADER_LEN + u64::from(BigEndian::read_u16(&buf[..2]));
+    if (buf.len() as u64) < end {
+        return None;
+    }
+    let command_buf = &buf[LEGACY_RECORD_HEADER_LEN as usize..end as usize];
+    if !is_plausible(command_buf) {
+        return None;
+    }
+    bincode::deserialize(command_buf)
+        .ok()
+        .map(|command| (command, end))
+}
+
+/// Rewrite the legacy log data file in `path` in the current format
+///
+/// The upgraded log is written under a temporary name and replaces the legacy one atomically.
+///
+/// Return `Ok` if success,
+/// return `Err(Error::Corruption)` if a legacy record is truncated or can't be deserialized,
+/// see `KvStore::fsck`,
+/// return `Err` when other error occurs
+pub(crate) fn upgrade_legacy_log(path: &Path) -> Result<()> {
+    let log_path = path.join(LOG_DATA_FILE_NAME);
+    let buf = fs::read(&log_path).map_err(|err| Error::from(err).at(&log_path))?;
+    let mut commands = Vec::new();
+    let mut offset = 0;
+    while offset < buf.len() {
+        match decode_legacy_record(&buf[offset..]) {
+            Some((command, len)) => {
+                commands.push(command);
+                offset += len as usize;
+            }
+            None => {
+                return Err(Error::Corruption {
+                    path: log_path,
+                    offset: offset as u64,
+                })
+            }
+        }
+    }
+    write_log_file(path, UPGRADE_TMP_FILE_NAME, &commands)
+}
+
+/// Replace the log data file in `path` by one holding `commands`
+///
+/// The log is written to `tmp_file_name` in `path` first, synced, then renamed.
+pub(crate) fn write_log_file(path: &Path, tmp_file_name: &str, commands: &[Command]) -> Result<()> {
+    let tmp_path = path.join(tmp_file_name);
+    write_log_to(&tmp_path, commands).map_err(|err| err.at(&tmp_path))?;
+    let log_path = path.join(LOG_DATA_FILE_NAME);
+    fs::rename(&tmp_path, &log_path).map_err(|err| Error::from(err).at(&log_path))
+}
+
+/// Write a log holding `commands` to the new file `file_path`, synced to disk
+fn write_log_to(file_path: &Path, commands: &[Command]) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(file_path)?);
+    write_log_header(&mut writer)?;
+    for command in commands {
+        write_command(&mut writer, command)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    Ok(())
+}
+
+/// Whether `command_buf` may hold a serialized command, cheaply checked before its checksum
+fn is_plausible(command_buf: &[u8]) -> bool {
+    command_buf.len() >= 4 && LittleEndian::read_u32(&command_buf[..4]) < COMMAND_VARIANTS
+}
+
+/// Maximum length of a serialized command, as held by the 2 bytes of its length
+pub(crate) const MAX_COMMAND_LEN: usize = u16::MAX as usize;
+
+/// Append the serialized `command` to `writer`
+///
+/// Return the number of bytes written,
+/// return `Err(Error::ValueTooLarge)` if the serialized command doesn't fit in a record,
+/// return `Err(Error::BatchTooLarge)` if the serialized batch doesn't fit in a record
+pub(crate) fn write_command<W: Write>(writer: &mut W, command: &Command) -> Result<u64> {
+    let encoded: Vec<u8> = bincode::serialize(command)?;
+    if encoded.len() > MAX_COMMAND_LEN {
+        return Err(match command {
+            Command::Set { key, .. } | Command::Get { key } | Command::Remove { key } => {
+                Error::ValueTooLarge {
+                    key: key.clone(),
+                    len: encoded.len(),
+                    max: MAX_COMMAND_LEN,
+                }
+            }
+            Command::Batch { .. } => Error::BatchTooLarge {
+                len: encoded.len(),
+                max: MAX_COMMAND_LEN,
+            },
+        });
+    }
+    writer.write_u16::<BigEndian>(encoded.len() as u16)?;
+    writer.write_u32::<BigEndian>(crc32fast::hash(&encoded))?;
+    writer.write_all(&encoded)?;
+    Ok(encoded.len() as u64 + RECORD_HEADER_LEN)
+}
+
+/// Split `commands` into batches which each fit in one record, keeping their order
+///
+/// Return `Ok(batches)` if success,
+/// return `Err(Error::ValueTooLarge)` if a command doesn't fit in a batch on its own
+pub(crate) fn split_batch(commands: Vec<Command>) -> Result<Vec<Vec<Command>>> {
+    let empty_len = bincode::serialized_size(&Command::Batch {
+        commands: Vec::new(),
+    })? as usize;
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_len = empty_len;
+    for command in commands {
+        let len = bincode::serialized_size(&command)? as usize;
+        if empty_len + len > MAX_COMMAND_LEN {
+            return Err(match command {
+                Command::Set { key, .. } | Command::Get { key } | Command::Remove { key } => {
+                    Error::ValueTooLarge {
+                        key,
+                        len,
+                        max: MAX_COMMAND_LEN,
+                    }
+                }
+                Command::Batch { .. } => Error::BatchTooLarge {
+                    len,
+                    max: MAX_COMMAND_LEN,
+                },
+            });
+        }
+        if batch_len + len > MAX_COMMAND_LEN {
+            batches.push(batch);
+            batch = Vec::new();
+            batch_len = empty_len;
+        }
+        batch.push(command);
+        batch_len += len;
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    Ok(batches)
+}
+
+/// Deserialize `command_buf` if it matches `checksum`
+fn decode(checksum: u32, command_buf: &[u8]) -> Option<Command> {
+    if crc32fast::hash(command_buf) != checksum {
+        return None;
+    }
+    bincode::deserialize(command_buf).ok()
+}
+
+/// Read the next record from `reader`
+///
+/// Return `Ok(Some((checksum, command_buf)))` if success,
+/// return `Ok(None)` when `reader` is exhausted right at a record boundary,
+/// return `Err` when the record is truncated
+fn read_frame<R: Read>(reader: &mut R) -> Result<Option<(u32, Vec<u8>)>> {
+    let mut header_buf = [0; RECORD_HEADER_LEN as usize];
+    if reader.read(&mut header_buf[..1])? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header_buf[1..])?;
+    let len = BigEndian::read_u16(&header_buf[..2]);
+    let checksum = BigEndian::read_u32(&header_buf[2..]);
+    let mut command_buf = vec![0; len as usize];
+    reader.read_exact(&mut command_buf)?;
+    Ok(Some((checksum, command_buf)))
+}
+
+/// Read the next command from `reader`
+///
+/// Return `Ok(Some((command, length)))` where `length` is the number of bytes read,
+/// return `Ok(None)` when `reader` is exhausted right at a record boundary,
+/// return `Err` when the record is truncated, corrupted or can't be deserialized
+pub(crate) fn read_command<R: Read>(reader: &mut R) -> Result<Option<(Command, u64)>> {
+    match read_frame(reader)? {
+        Some((checksum, command_buf)) => match decode(checksum, &command_buf) {
+            Some(command) => Ok(Some((
+                command,
+                command_buf.len() as u64 + RECORD_HEADER_LEN,
+            ))),
+            None => Err(io::Error::new(io::ErrorKind::InvalidData, "corrupted record").into()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Decode the record at the start of `buf`
+///
+/// Return `Some((command, length))` if `buf` starts with a whole record whose checksum
+/// matches, return `None` otherwise
+pub(crate) fn decode_record(buf: &[u8]) -> Option<(Command, u64)> {
+    if (buf.len() as u64) < RECORD_HEADER_LEN {
+        return None;
+    }
+    let len = u64::from(BigEndian::read_u16(&buf[..2]));
+    let checksum = BigEndian::read_u32(&buf[2..6]);
+    let end = RECORD_HEADER_LEN + len;
+    if (buf.len() as u64) < end {
+        return None;
+    }
+    let command_buf = &buf[RECORD_HEADER_LEN as usize..end as usize];
+    if !is_plausible(command_buf) {
+        return None;
+    }
+    decode(checksum, command_buf).map(|command| (command, end))
+}
+
+/// One record of the log
+#[derive(Debug)]
+pub struct LogRecord {
+    /// Offset of the record in the log
+    pub offset: u64,
+    /// Length of the record in bytes, framing included
+    pub len: u64,
+    /// Recorded command, `None` if the checksum doesn't match or it can't be deserialized
+    pub command: Option<Command>,
+}
+
+/// Reads the records of a log one at a time
+///
+/// The header starting the log is checked first, offsets count from the start of the log.
+/// Iterating yields `Err` once if the header is missing or holds another version, if a record
+/// is truncated or if reading fails, and stops there.
+/// A record which is corrupted or can't be deserialized is yielded with `command: None` and
+/// reading goes on at the end of it as given by its length, which may be corrupted too.
+#[derive(Debug)]
+pub struct LogReader<R> {
+    reader: R,
+    offset: u64,
+    done: bool,
+}
+
+impl LogReader<BufReader<File>> {
+    /// Open the log data file of the KV store in `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        let log_path = path.join(LOG_DATA_FILE_NAME);
+        let file = File::open(&log_path).map_err(|err| Error::from(err).at(&log_path))?;
+        Ok(LogReader::new(BufReader::new(file)))
+    }
+}
+
+impl<R: Read> LogReader<R> {
+    /// Read records from the start of `reader`
+    pub fn new(reader: R) -> Self {
+        LogReader {
+            reader,
+            offset: 0,
+            done: false,
+        }
+    }
+
+    /// Offset of the next record to read
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Read and check the header starting the log
+    ///
+    /// Return `Ok(false)` if the log is empty
+    fn read_header(&mut self) -> Result<bool> {
+        let mut header_buf = [0; LOG_HEADER_LEN as usize];
+        if self.reader.read(&mut header_buf[..1])? == 0 {
+            return Ok(false);
+        }
+        self.reader.read_exact(&mut header_buf[1..])?;
+        if !header_buf.starts_with(LOG_MAGIC) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a current kvs log").into());
+        }
+        let version = BigEndian::read_u16(&header_buf[LOG_MAGIC.len()..]);
+        if version != LOG_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported log version {}", version),
+            )
+            .into());
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Iterator for LogReader<R> {
+    type Item = Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.offset == 0 {
+            match self.read_header() {
+                Ok(true) => self.offset = LOG_HEADER_LEN,
+                Ok(false) => {
+                    self.done = true;
+                    return None;
+                }
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        match read_frame(&mut self.reader) {
+            Ok(Some((checksum, command_buf))) => {
+                let record = LogRecord {
+                    offset: self.offset,
+                    len: command_buf.len() as u64 + RECORD_HEADER_LEN,
+                    command: decode(checksum, &command_buf),
+                };
+                self.offset += record.len;
+                Some(Ok(record))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
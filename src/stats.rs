@@ -0,0 +1,79 @@
+//! Statistics of the KV store
+use super::record::{LOG_HEADER_LEN, RECORD_HEADER_LEN};
+use super::{Command, Error, KvStore, Result, LOG_DATA_FILE_NAME};
+use serde::Serialize;
+use std::mem;
+
+/// Snapshot of the KV store's statistics
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    /// Number of live keys
+    pub live_keys: u64,
+    /// Length of the log in bytes, header included
+    pub log_bytes: u64,
+    /// Bytes of the log not holding the current data, reclaimed by compaction
+    pub stale_bytes: u64,
+    /// Number of log files
+    pub segments: u64,
+    /// Seconds since the Unix epoch of the last compaction, `None` if the store never compacted
+    ///
+    /// Read back when the store is opened, unless saving it failed.
+    pub last_compaction: Option<u64>,
+    /// Average length of the live keys in bytes
+    pub avg_key_size: f64,
+    /// Average length of the live values in bytes
+    pub avg_value_size: f64,
+    /// Estimated memory used by the in-memory index in bytes
+    pub index_memory: u64,
+}
+
+impl KvStore {
+    /// Collect the statistics of the KV store
+    ///
+    /// Return `Ok(stats)` if success,
+    /// return `Err` if the log's length can't be read
+    pub fn stats(&self) -> Result<Stats> {
+        let log_bytes = self
+            .log_file
+            .metadata()
+            .map_err(|err| Error::from(err).at(&self.path.join(LOG_DATA_FILE_NAME)))?
+            .len();
+
+        let mut live_bytes = 0;
+        let mut key_bytes = 0;
+        let mut value_bytes = 0;
+        let mut index_memory = self.key_offset_map.capacity()
+            * (mem::size_of::<(String, u64)>() + 1)
+            + self.key_value_map.capacity() * (mem::size_of::<(String, String)>() + 1);
+        for (key, value) in self.key_value_map.iter() {
+            let command = Command::Set {
+                key: key.clone(),
+                value: value.clone(),
+            };
+            live_bytes += bincode::serialized_size(&command)? + RECORD_HEADER_LEN;
+            key_bytes += key.len();
+            value_bytes += value.len();
+            // The key is held by both maps
+            index_memory += key.capacity() * 2 + value.capacity();
+        }
+
+        let live_keys = self.key_value_map.len();
+        let average = |bytes: usize| {
+            if live_keys == 0 {
+                0.0
+            } else {
+                bytes as f64 / live_keys as f64
+            }
+        };
+        Ok(Stats {
+            live_keys: live_keys as u64,
+            log_bytes,
+            stale_bytes: log_bytes.saturating_sub(LOG_HEADER_LEN + live_bytes),
+            segments: 1,
+            last_compaction: self.last_compaction,
+            avg_key_size: average(key_bytes),
+            avg_value_size: average(value_bytes),
+            index_memory: index_memory as u64,
+        })
+    }
+}
@@ -1,7 +1,12 @@
 use assert_cmd::prelude::*;
-use kvs::{KvStore, Result};
+use kvs::Command as LogCommand;
+use kvs::{
+    CorruptRegion, DataFormat, Error, ImportMode, KvStore, LogReader, LogRecord, RestorePoint,
+    Result,
+};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::fs;
 use std::process::Command;
 use tempfile::TempDir;
 use walkdir::WalkDir;
@@ -17,35 +22,39 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
-// `kvs get <KEY>` should print "Key not found" for a non-existent key and exit with zero.
+// `kvs get <KEY>` should print "key not found: <KEY>" on stderr for a non-existent key and exit
+// with the not found code.
 #[test]
 fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(eq("Key not found").trim());
+        .code(1)
+        .stdout(is_empty())
+        .stderr(contains("key not found: key1"));
 }
 
-// `kvs rm <KEY>` should print "Key not found" for an empty database and exit with non-zero code.
+// `kvs rm <KEY>` should print "key not found: <KEY>" on stderr for an empty database and exit
+// with the not found code.
 #[test]
 fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
-        .failure()
-        .stdout(eq("Key not found").trim());
+        .code(1)
+        .stdout(is_empty())
+        .stderr(contains("key not found: key1"));
 }
 
 // `kvs set <KEY> <VALUE>` should print nothing and exit with zero.
@@ -54,7 +63,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -72,7 +81,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -80,7 +89,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -100,7 +109,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -108,11 +117,12 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
-        .success()
-        .stdout(eq("Key not found").trim());
+        .code(1)
+        .stdout(is_empty())
+        .stderr(contains("key not found: key1"));
 
     Ok(())
 }
@@ -121,13 +131,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -136,19 +146,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -157,13 +167,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -172,7 +182,7 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
@@ -299,3 +309,1374 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should restore the data as of the backup, while the store stays writable after backing up
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.backup(backup_dir.path())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    KvStore::restore(backup_dir.path(), restore_dir.path())?;
+    let mut store = KvStore::open(restore_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should reject a backup whose data doesn't match its manifest, or which has no manifest
+#[test]
+fn restore_invalid_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.backup(backup_dir.path())?;
+
+    let data_path = backup_dir.path().join("full-0000000001.data");
+    let mut data = fs::read(&data_path)?;
+    let last = data.len() - 1;
+    data[last] ^= 0xff;
+    fs::write(&data_path, &data)?;
+    match KvStore::restore(backup_dir.path(), restore_dir.path()) {
+        Err(Error::BadBackupRecord { offset, .. }) => assert_eq!(offset, 0),
+        other => panic!("expected a bad backup record, got {:?}", other),
+    }
+
+    // A truncated record is reported at its start, with the error reading it as the source
+    data[last] ^= 0xff;
+    data.truncate(last - 2);
+    fs::write(&data_path, &data)?;
+    let err = match KvStore::restore(backup_dir.path(), restore_dir.path()) {
+        Err(err) => err,
+        Ok(()) => panic!("expected a bad backup record"),
+    };
+    match &err {
+        Error::BadBackupRecord { path, offset, .. } => {
+            assert_eq!(path, &data_path);
+            assert_eq!(*offset, 0);
+        }
+        other => panic!("expected a bad backup record, got {:?}", other),
+    }
+    assert!(std::error::Error::source(&err).is_some());
+
+    // A missing manifest can't be read rather than being invalid
+    store.backup(backup_dir.path())?;
+    let manifest_path = backup_dir.path().join("backup.manifest");
+    fs::remove_file(&manifest_path)?;
+    match KvStore::restore(backup_dir.path(), restore_dir.path()) {
+        Err(Error::File { path, .. }) => assert_eq!(path, manifest_path),
+        other => panic!("expected the manifest to be missing, got {:?}", other),
+    }
+    assert!(!restore_dir.path().join("log.data").exists());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "--output",
+            "json",
+            "restore",
+            backup_dir.path().to_str().unwrap(),
+        ])
+        .current_dir(&restore_dir)
+        .assert()
+        .code(3)
+        .stderr(contains(r#""kind":"io""#));
+
+    Ok(())
+}
+
+// Should refuse to back up into a store's data directory, its own included, and to restore
+// into a backup directory
+#[test]
+fn backup_into_store_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut other = KvStore::open(other_dir.path())?;
+    other.set("precious".to_owned(), "value1".to_owned())?;
+    drop(other);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for dest in [other_dir.path(), temp_dir.path()] {
+        match store.backup(dest) {
+            Err(Error::NotBackupDir(path)) => assert_eq!(path, dest),
+            other => panic!("expected the backup to be refused, got {:?}", other),
+        }
+        match store.backup_incremental(dest) {
+            Err(Error::NotBackupDir(_)) => {}
+            other => panic!("expected the backup to be refused, got {:?}", other),
+        }
+    }
+
+    // Nor can a backup directory be restored into, its own included
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    store.backup(backup_dir.path())?;
+    match KvStore::restore(backup_dir.path(), backup_dir.path()) {
+        Err(Error::NotStoreDir(path)) => assert_eq!(path, backup_dir.path()),
+        other => panic!("expected the restore to be refused, got {:?}", other),
+    }
+    store.backup_incremental(backup_dir.path())?;
+    drop(store);
+
+    let mut other = KvStore::open(other_dir.path())?;
+    assert_eq!(other.get("precious".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A new full backup should replace the previous chain only once it is complete
+#[test]
+fn backup_replaces_chain() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.backup(backup_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.backup_incremental(backup_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.backup(backup_dir.path())?;
+
+    let mut names: Vec<String> = fs::read_dir(backup_dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["backup.manifest", "full-0000000002.data"]);
+
+    KvStore::restore(backup_dir.path(), restore_dir.path())?;
+    let mut store = KvStore::open(restore_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// `kvs backup <DIR>` then `kvs restore <DIR>` should bring back the backed up data
+#[test]
+fn cli_backup_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["backup", backup_dir.path().to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["rm", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", backup_dir.path().to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Ok(())
+}
+
+// Should restore the chain of backups up to the chosen sequence number
+#[test]
+fn incremental_backup_and_restore_until() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.backup(backup_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.backup_incremental(backup_dir.path())?, 2);
+    store.remove("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.backup_incremental(backup_dir.path())?, 3);
+
+    let backups = KvStore::list_backups(backup_dir.path())?;
+    let records: Vec<(u64, bool, u64)> = backups
+        .iter()
+        .map(|backup| (backup.seq, backup.incremental, backup.records))
+        .collect();
+    assert_eq!(records, vec![(1, false, 2), (2, true, 1), (3, true, 2)]);
+
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    KvStore::restore_until(backup_dir.path(), restore_dir.path(), RestorePoint::Seq(2))?;
+    let mut restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(restored.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(restored.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(restored);
+
+    KvStore::restore(backup_dir.path(), restore_dir.path())?;
+    let mut restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, None);
+    assert_eq!(restored.get("key2".to_owned())?, None);
+    assert_eq!(restored.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    match KvStore::restore_until(backup_dir.path(), restore_dir.path(), RestorePoint::Seq(0)) {
+        Err(Error::InvalidBackup(_)) => {}
+        other => panic!("expected an invalid backup, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// Should restore the backups taken at or before a time, with `kvs restore --time` too
+#[test]
+fn restore_until_time() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.backup(backup_dir.path())?;
+    // Backup times are in seconds
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    store.backup_incremental(backup_dir.path())?;
+    drop(store);
+
+    let backups = KvStore::list_backups(backup_dir.path())?;
+    let first = backups[0].created_at;
+    assert!(backups[1].created_at > first);
+
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    match KvStore::restore_until(
+        backup_dir.path(),
+        restore_dir.path(),
+        RestorePoint::Time(first - 1),
+    ) {
+        Err(Error::InvalidBackup(_)) => {}
+        other => panic!("expected no backup before the first one, got {:?}", other),
+    }
+    assert!(!restore_dir.path().join("log.data").exists());
+
+    KvStore::restore_until(
+        backup_dir.path(),
+        restore_dir.path(),
+        RestorePoint::Time(first),
+    )?;
+    let mut restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(restored);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", backup_dir.path().to_str().unwrap(), "--time"])
+        .arg((first - 1).to_string())
+        .current_dir(&restore_dir)
+        .assert()
+        .code(4);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["restore", backup_dir.path().to_str().unwrap(), "--time"])
+        .arg(backups[1].created_at.to_string())
+        .current_dir(&restore_dir)
+        .assert()
+        .success();
+    let mut restored = KvStore::open(restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `kvs --dir <DIR>` and `KVS_DIR` should use the given directory, creating it if missing
+#[test]
+fn cli_dir() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_dir = temp_dir.path().join("data");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--dir", data_dir.to_str().unwrap(), "set", "key1", "value1"])
+        .assert()
+        .success();
+    assert!(data_dir.join("log.data").exists());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .env("KVS_DIR", &data_dir)
+        .args(["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stdout(is_empty())
+        .stderr(contains("key not found: key1"));
+}
+
+// `kvs --dir <FILE>` should fail with a message on stderr
+#[test]
+fn cli_dir_not_a_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file_path = temp_dir.path().join("file");
+    fs::write(&file_path, b"")?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--dir", file_path.to_str().unwrap(), "get", "key1"])
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("is not a directory"));
+
+    Ok(())
+}
+
+// Should round-trip the live data through every export format
+#[test]
+fn export_and_import() -> Result<()> {
+    for format in [DataFormat::JsonLines, DataFormat::Csv].iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value, \"quoted\"".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+
+        let mut exported = Vec::new();
+        assert_eq!(store.export(&mut exported, *format)?, 2);
+
+        let import_dir = TempDir::new().expect("unable to create temporary import directory");
+        let mut imported = KvStore::open(import_dir.path())?;
+        let summary = imported.import(&exported[..], *format, ImportMode::Overwrite)?;
+        assert_eq!(summary.imported, 2);
+        drop(imported);
+
+        let mut imported = KvStore::open(import_dir.path())?;
+        assert_eq!(
+            imported.get("key1".to_owned())?,
+            Some("value, \"quoted\"".to_owned())
+        );
+        assert_eq!(imported.get("key2".to_owned())?, None);
+        assert_eq!(imported.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should keep existing values with `ImportMode::SkipExisting`, and import nothing from
+// malformed input
+#[test]
+fn import_modes_and_malformed_input() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let input = "{\"key\":\"key1\",\"value\":\"new1\"}\n{\"key\":\"key2\",\"value\":\"new2\"}\n";
+    let summary = store.import(
+        input.as_bytes(),
+        DataFormat::JsonLines,
+        ImportMode::SkipExisting,
+    )?;
+    assert_eq!(summary.imported, 1);
+    assert_eq!(summary.skipped, 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("new2".to_owned()));
+
+    let summary = store.import(
+        input.as_bytes(),
+        DataFormat::JsonLines,
+        ImportMode::Overwrite,
+    )?;
+    assert_eq!(summary.imported, 2);
+    assert_eq!(store.get("key1".to_owned())?, Some("new1".to_owned()));
+
+    let input = "key,value\nkey3,value3\nkey4\n";
+    match store.import(input.as_bytes(), DataFormat::Csv, ImportMode::Overwrite) {
+        Err(Error::InvalidImport(_)) => {}
+        other => panic!("expected an invalid import, got {:?}", other),
+    }
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// `kvs export` then `kvs import` should move the data to another directory
+#[test]
+fn cli_export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["export", "--format", "csv", "out.csv"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--dir", "imported", "import", "--format", "csv", "out.csv"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--dir", "imported", "export"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("{\"key\":\"key1\",\"value\":\"value1\"}").trim());
+
+    // A missing input file is named in the error
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["import", "missing.jsonl"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3)
+        .stderr(contains("missing.jsonl"));
+
+    Ok(())
+}
+
+// Should scan the live pairs with a given key prefix, ordered by key
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("b2".to_owned(), "value2".to_owned())?;
+    store.set("a1".to_owned(), "value1".to_owned())?;
+    store.set("b1".to_owned(), "value3".to_owned())?;
+    store.remove("a1".to_owned())?;
+
+    assert_eq!(
+        store.scan("b"),
+        vec![
+            ("b1".to_owned(), "value3".to_owned()),
+            ("b2".to_owned(), "value2".to_owned())
+        ]
+    );
+    assert_eq!(store.scan("").len(), 2);
+    assert!(store.scan("a").is_empty());
+
+    Ok(())
+}
+
+// `kvs shell` should run every line against one open store, with quoting
+#[test]
+fn cli_shell() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["shell"])
+        .env("HOME", temp_dir.path())
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key1 \"value with spaces\"\nset key2 'it''s'\nget key1\nscan key\nrm key3\nget key4\nunknown\nexit\nget key2\n")
+        .assert()
+        .success()
+        .stdout(eq("value with spaces\nkey1\tvalue with spaces\nkey2\tits\n"))
+        .stderr(contains("key not found: key3"))
+        .stderr(contains("key not found: key4"))
+        .stderr(contains("unknown command"));
+}
+
+// Should read the log record by record, flagging undecodable and truncated records
+#[test]
+fn log_reader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("log.data");
+    let mut log = fs::read(&log_path)?;
+    log.extend_from_slice(&[0, 3, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0, 9]);
+    fs::write(&log_path, &log)?;
+
+    let records: Vec<Result<LogRecord>> = LogReader::open(temp_dir.path())?.collect();
+    assert_eq!(records.len(), 4);
+    match &records[0] {
+        Ok(record) => {
+            assert_eq!(record.offset, 8);
+            assert_eq!(
+                record.command,
+                Some(LogCommand::Set {
+                    key: "key1".to_owned(),
+                    value: "value1".to_owned()
+                })
+            );
+        }
+        Err(err) => panic!("unexpected error {:?}", err),
+    }
+    match &records[1] {
+        Ok(record) => {
+            assert_eq!(record.offset, 8 + records[0].as_ref().unwrap().len);
+            assert_eq!(
+                record.command,
+                Some(LogCommand::Remove {
+                    key: "key1".to_owned()
+                })
+            );
+        }
+        Err(err) => panic!("unexpected error {:?}", err),
+    }
+    match &records[2] {
+        Ok(record) => {
+            assert_eq!(record.len, 9);
+            assert_eq!(record.command, None);
+        }
+        Err(err) => panic!("unexpected error {:?}", err),
+    }
+    assert!(records[3].is_err());
+
+    Ok(())
+}
+
+// `kvs dump` should print one line per record
+#[test]
+fn cli_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("8\t36\tset\tkey1\tvalue1\n44\t22\trm\tkey1\t\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump", "--json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(
+            "{\"key\":\"key1\",\"len\":22,\"offset\":44,\"type\":\"rm\",\"value\":null}",
+        ));
+
+    // A truncated record ends the listing, while a log without a header fails
+    let log_path = temp_dir.path().join("log.data");
+    let mut log = fs::read(&log_path)?;
+    log.extend_from_slice(&[0, 9]);
+    fs::write(&log_path, &log)?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("66\t-\ttruncated"));
+
+    write_legacy_log(temp_dir.path())?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("not a current kvs log"));
+
+    Ok(())
+}
+
+// Should report live, stale and corrupt bytes, and repair by keeping every intact record
+#[test]
+fn fsck_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let report = KvStore::fsck(temp_dir.path(), false)?;
+    assert!(report.is_clean());
+    assert_eq!(report.records, 3);
+    assert_eq!(report.live_bytes, 36);
+    assert_eq!(report.stale_bytes, 36 + 22);
+
+    // Damage the record setting key1 and leave a truncated one at the end
+    let key1_offset = LogReader::open(temp_dir.path())?
+        .map(|record| record.unwrap())
+        .find(|record| match &record.command {
+            Some(LogCommand::Set { key, .. }) => key == "key1",
+            _ => false,
+        })
+        .map(|record| record.offset)
+        .unwrap();
+    let log_path = temp_dir.path().join("log.data");
+    let mut log = fs::read(&log_path)?;
+    log[key1_offset as usize + 10] ^= 0xff;
+    log.extend_from_slice(&[0, 9]);
+    fs::write(&log_path, &log)?;
+
+    let report = KvStore::fsck(temp_dir.path(), false)?;
+    assert_eq!(report.records, 2);
+    assert_eq!(report.live_bytes, 36);
+    assert_eq!(report.stale_bytes, 22);
+    assert_eq!(
+        report.corrupt_regions,
+        vec![
+            CorruptRegion {
+                offset: key1_offset,
+                len: 36
+            },
+            CorruptRegion {
+                offset: 8 + 36 * 2 + 22,
+                len: 2
+            }
+        ]
+    );
+    assert!(!report.repaired);
+
+    let report = KvStore::fsck(temp_dir.path(), true)?;
+    assert!(report.repaired);
+    assert!(KvStore::fsck(temp_dir.path(), false)?.is_clean());
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// `kvs fsck` should fail on a corrupt log, and succeed once repaired
+#[test]
+fn cli_fsck() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("corrupt regions: 0"));
+
+    let log_path = temp_dir.path().join("log.data");
+    let mut log = fs::read(&log_path)?;
+    log.extend_from_slice(&[0, 9]);
+    fs::write(&log_path, &log)?;
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4)
+        .stdout(contains("offset 44, length 2"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck", "--repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("repaired"));
+
+    Ok(())
+}
+
+// Should report the live keys, live and stale bytes, and the last compaction
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 0);
+    assert_eq!(stats.log_bytes, 8);
+    assert_eq!(stats.last_compaction, None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value22".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.log_bytes, 8 + 36 + 37 + 22);
+    assert_eq!(stats.stale_bytes, 36 + 22);
+    assert_eq!(stats.segments, 1);
+    assert_eq!(stats.avg_key_size, 4.0);
+    assert_eq!(stats.avg_value_size, 7.0);
+    assert!(stats.index_memory > 0);
+    let last_compaction = stats.last_compaction;
+    assert!(last_compaction.is_some());
+
+    // Writes which don't compact, even failing ones, leave the last compaction as it was
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    assert!(store.remove("key3".to_owned()).is_err());
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.last_compaction, last_compaction);
+
+    Ok(())
+}
+
+// `kvs stats --json` should print the statistics as one JSON object
+#[test]
+fn cli_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("live keys: 1\nlog bytes: 44\nstale bytes: 0\n"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["stats", "--json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(
+            "{\"live_keys\":1,\"log_bytes\":44,\"stale_bytes\":0,",
+        ));
+
+    Ok(())
+}
+
+// Should apply a batch in order, or leave the store untouched if a command fails
+#[test]
+fn apply_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let results = store.apply_batch(&[
+        LogCommand::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        LogCommand::Get {
+            key: "key2".to_owned(),
+        },
+        LogCommand::Remove {
+            key: "key1".to_owned(),
+        },
+        LogCommand::Get {
+            key: "key1".to_owned(),
+        },
+    ])?;
+    assert_eq!(
+        results,
+        vec![
+            None,
+            Some("value2".to_owned()),
+            Some("value1".to_owned()),
+            None
+        ]
+    );
+
+    let result = store.apply_batch(&[
+        LogCommand::Set {
+            key: "key3".to_owned(),
+            value: "value3".to_owned(),
+        },
+        LogCommand::Remove {
+            key: "key1".to_owned(),
+        },
+    ]);
+    match result {
+        Err(Error::KeyNotFound(key)) => assert_eq!(key, "key1"),
+        other => panic!("expected key not found, got {:?}", other),
+    }
+
+    // The compacted log replaced the old one whole
+    assert!(!temp_dir.path().join("log.data.compact").exists());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// A batch is one record, replayed whole or, once damaged, dropped whole by a repair
+#[test]
+fn batch_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let batch = LogCommand::Batch {
+        commands: vec![
+            LogCommand::Set {
+                key: "key2".to_owned(),
+                value: "value2".to_owned(),
+            },
+            LogCommand::Remove {
+                key: "key1".to_owned(),
+            },
+        ],
+    };
+    let log_path = temp_dir.path().join("log.data");
+    let clean_log = fs::read(&log_path)?;
+    let mut log = clean_log.clone();
+    log.extend_from_slice(&log_record(&batch));
+    fs::write(&log_path, &log)?;
+
+    let records: Vec<LogRecord> = LogReader::open(temp_dir.path())?
+        .map(|record| record.unwrap())
+        .collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[1].offset, clean_log.len() as u64);
+    assert_eq!(records[1].command, Some(batch.clone()));
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    // Damaging the batch drops all of it
+    log[clean_log.len() + 20] ^= 0xff;
+    fs::write(&log_path, &log)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    KvStore::fsck(temp_dir.path(), true)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    // So does truncating it
+    let mut log = clean_log.clone();
+    log.extend_from_slice(&log_record(&batch));
+    log.truncate(log.len() - 5);
+    fs::write(&log_path, &log)?;
+    KvStore::fsck(temp_dir.path(), true)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // A batch which doesn't fit in one record is refused, while import splits its input
+    let commands: Vec<LogCommand> = (0..2000)
+        .map(|i| LogCommand::Set {
+            key: format!("key{}", i),
+            value: "x".repeat(32),
+        })
+        .collect();
+    match store.apply_batch(&commands) {
+        Err(Error::BatchTooLarge { max, .. }) => assert_eq!(max, 65535),
+        other => panic!("expected the batch to be too large, got {:?}", other),
+    }
+    assert_eq!(store.get("key1999".to_owned())?, None);
+    let value = "x".repeat(32);
+    let input: String = (0..2000)
+        .map(|i| format!("{{\"key\":\"key{}\",\"value\":\"{}\"}}\n", i, value))
+        .collect();
+    let summary = store.import(
+        input.as_bytes(),
+        DataFormat::JsonLines,
+        ImportMode::Overwrite,
+    )?;
+    assert_eq!(summary.imported, 2000);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1999".to_owned())?, Some("x".repeat(32)));
+
+    Ok(())
+}
+
+// `kvs batch` should run every line in order, and `--atomic` should apply all or nothing
+#[test]
+fn cli_batch() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key1 value1\n# comment\n\nset key2 \"value 2\"\nget key2\nunknown\nget key1\n")
+        .assert()
+        .failure()
+        .stdout(eq("value 2\nvalue1\n"))
+        .stderr(contains("line 6: unknown command"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch", "--atomic"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("set key3 value3\nrm key4\n")
+        .assert()
+        .failure()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch", "--atomic"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("get key3\nrm key1\nget key1\nget key2\n")
+        .assert()
+        .code(1)
+        .stdout(eq("value 2\n"))
+        .stderr(contains("line 1: key not found: key3"))
+        .stderr(contains("line 3: key not found: key1"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["batch"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("get key1\nget key2\n")
+        .assert()
+        .code(1)
+        .stdout(eq("value 2\n"))
+        .stderr(contains("line 1: key not found: key1"));
+}
+
+// The CLI should exit with the code of the kind of failure
+#[test]
+fn cli_exit_codes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["unknown", "subcommand"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--help"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("USAGE"));
+
+    let file_path = temp_dir.path().join("file");
+    fs::write(&file_path, b"")?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--dir", file_path.to_str().unwrap(), "get", "key1"])
+        .assert()
+        .code(2);
+
+    let store = KvStore::open(temp_dir.path())?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(3)
+        .stderr(contains("locked"));
+    drop(store);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let log_path = temp_dir.path().join("log.data");
+    let mut log = fs::read(&log_path)?;
+    log[8 + 10] ^= 0xff;
+    fs::write(&log_path, &log)?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4)
+        .stderr(contains("bad record at offset 8"));
+
+    Ok(())
+}
+
+// `--output json` should print results and errors as JSON objects
+#[test]
+fn cli_output_json() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"key":"key1","value":"value1"}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--output", "json"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq(r#"{"key":"key1","value":"value1"}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "rm", "key2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stdout(is_empty())
+        .stderr(eq(r#"{"code":1,"error":"key not found: key2","kind":"not_found"}"#).trim());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "fsck"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains(r#""corrupt_regions":[]"#));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "batch"])
+        .current_dir(&temp_dir)
+        .with_stdin()
+        .buffer("get key1\nget key2\nscan\n")
+        .assert()
+        .code(1)
+        .stdout(eq(concat!(
+            r#"{"key":"key1","value":"value1"}"#,
+            "\n",
+            r#"{"key":"key1","value":"value1"}"#,
+            "\n"
+        )))
+        .stderr(
+            eq(r#"{"code":1,"error":"line 2: key not found: key2","kind":"not_found"}"#).trim(),
+        );
+
+    // Usage errors too
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--output", "json", "unknown"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(2)
+        .stdout(is_empty())
+        .stderr(contains(r#""kind":"usage""#))
+        .stderr(contains("'unknown'"));
+}
+
+// A data directory can only be opened by one store at a time
+#[test]
+fn open_locked_dir() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(Error::Locked(path)) => assert_eq!(path, temp_dir.path()),
+        _ => panic!("expected the directory to be locked"),
+    }
+    match KvStore::fsck(temp_dir.path(), false) {
+        Err(Error::Locked(_)) => {}
+        _ => panic!("expected the directory to be locked"),
+    }
+
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+
+    Ok(())
+}
+
+// Values which don't fit in a record are rejected without touching the store
+#[test]
+fn set_value_too_large() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match store.set("key2".to_owned(), "x".repeat(70_000)) {
+        Err(Error::ValueTooLarge { key, len, max }) => {
+            assert_eq!(key, "key2");
+            assert!(len > max);
+            assert_eq!(max, 65535);
+        }
+        _ => panic!("expected the value to be too large"),
+    }
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // So can a key alone
+    let key = "k".repeat(70_000);
+    match store.remove(key.clone()) {
+        Err(err @ Error::ValueTooLarge { .. }) => assert!(err
+            .to_string()
+            .starts_with(&format!("record for key {} is too large", key))),
+        _ => panic!("expected the key to be too large"),
+    }
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Opening a log with a bad record fails until it is repaired
+#[test]
+fn open_corrupted_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    // Damage the checksum of the remove record
+    let log_path = temp_dir.path().join("log.data");
+    let mut log = fs::read(&log_path)?;
+    log[44 + 2] ^= 0xff;
+    fs::write(&log_path, &log)?;
+
+    let err = match KvStore::open(temp_dir.path()) {
+        Err(err) => err,
+        Ok(_) => panic!("expected the log to be corrupted"),
+    };
+    match &err {
+        Error::Corruption { path, offset } => {
+            assert_eq!(path, &log_path);
+            assert_eq!(*offset, 44);
+        }
+        _ => panic!("expected the log to be corrupted"),
+    }
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "{}: bad record at offset 44, run `kvs fsck --repair` to drop the bad records",
+            log_path.display()
+        )
+    );
+
+    KvStore::fsck(temp_dir.path(), true)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // A record cut off at the end, as a crash while appending leaves it, is dropped on open
+    let mut log = fs::read(&log_path)?;
+    log.extend_from_slice(&[0, 9, 0]);
+    fs::write(&log_path, &log)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(fs::metadata(&log_path)?.len(), 44);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    assert!(KvStore::fsck(temp_dir.path(), false)?.is_clean());
+
+    Ok(())
+}
+
+/// Append `command` serialized the way bincode does to `encoded`
+fn encode_command(encoded: &mut Vec<u8>, command: &LogCommand) {
+    let push_str = |encoded: &mut Vec<u8>, s: &str| {
+        encoded.extend_from_slice(&(s.len() as u64).to_le_bytes());
+        encoded.extend_from_slice(s.as_bytes());
+    };
+    match command {
+        LogCommand::Set { key, value } => {
+            encoded.extend_from_slice(&0u32.to_le_bytes());
+            push_str(encoded, key);
+            push_str(encoded, value);
+        }
+        LogCommand::Get { key } => {
+            encoded.extend_from_slice(&1u32.to_le_bytes());
+            push_str(encoded, key);
+        }
+        LogCommand::Remove { key } => {
+            encoded.extend_from_slice(&2u32.to_le_bytes());
+            push_str(encoded, key);
+        }
+        LogCommand::Batch { commands } => {
+            encoded.extend_from_slice(&3u32.to_le_bytes());
+            encoded.extend_from_slice(&(commands.len() as u64).to_le_bytes());
+            for command in commands {
+                encode_command(encoded, command);
+            }
+        }
+    }
+}
+
+/// Frame `command` as the log did before the header and the checksums were introduced
+fn legacy_record(command: &LogCommand) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    encode_command(&mut encoded, command);
+    let mut record = (encoded.len() as u16).to_be_bytes().to_vec();
+    record.extend_from_slice(&encoded);
+    record
+}
+
+/// Frame `command` as a checksummed record
+fn log_record(command: &LogCommand) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    encode_command(&mut encoded, command);
+    let mut record = (encoded.len() as u16).to_be_bytes().to_vec();
+    record.extend_from_slice(&crc32fast::hash(&encoded).to_be_bytes());
+    record.extend_from_slice(&encoded);
+    record
+}
+
+/// Write a legacy log setting key1 and key2, then removing key1
+fn write_legacy_log(path: &std::path::Path) -> Result<Vec<u8>> {
+    let mut log = Vec::new();
+    for command in [
+        LogCommand::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+        LogCommand::Set {
+            key: "key2".to_owned(),
+            value: "value2".to_owned(),
+        },
+        LogCommand::Remove {
+            key: "key1".to_owned(),
+        },
+    ] {
+        log.extend_from_slice(&legacy_record(&command));
+    }
+    fs::write(path.join("log.data"), &log)?;
+    Ok(log)
+}
+
+// A log written before the header and the checksums should be upgraded when opened
+#[test]
+fn open_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_legacy_log(temp_dir.path())?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+
+    let log = fs::read(temp_dir.path().join("log.data"))?;
+    assert!(log.starts_with(b"KVSLOG"));
+    let records: Vec<LogRecord> = LogReader::open(temp_dir.path())?
+        .map(|record| record.unwrap())
+        .collect();
+    assert_eq!(records.len(), 3);
+
+    // A legacy log damaged after its first record is refused until repaired
+    let mut log = write_legacy_log(temp_dir.path())?;
+    let len = log.len();
+    log.truncate(len - 3);
+    fs::write(temp_dir.path().join("log.data"), &log)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(Error::Corruption { offset, .. }) => assert_eq!(offset, 32 * 2),
+        other => panic!("expected the log to be corrupted, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// fsck should report a legacy log and upgrade it on repair, keeping every intact record
+#[test]
+fn fsck_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = write_legacy_log(temp_dir.path())?;
+
+    let report = KvStore::fsck(temp_dir.path(), false)?;
+    assert!(report.legacy);
+    assert!(report.is_clean());
+    assert_eq!(report.records, 3);
+    assert!(!report.repaired);
+    assert_eq!(fs::read(temp_dir.path().join("log.data"))?, log);
+
+    let report = KvStore::fsck(temp_dir.path(), true)?;
+    assert!(report.repaired);
+    let report = KvStore::fsck(temp_dir.path(), false)?;
+    assert!(!report.legacy);
+    assert_eq!(report.records, 3);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// fsck --repair should leave a log holding no intact record untouched
+#[test]
+fn fsck_unrepairable_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("log.data");
+    let garbage = vec![0xff; 100];
+    fs::write(&log_path, &garbage)?;
+
+    match KvStore::fsck(temp_dir.path(), true) {
+        Err(Error::Unrepairable(path)) => assert_eq!(path, log_path),
+        other => panic!("expected the log to be unrepairable, got {:?}", other),
+    }
+    assert_eq!(fs::read(&log_path)?, garbage);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck", "--repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(4)
+        .stderr(contains("no intact record found"));
+    assert_eq!(fs::read(&log_path)?, garbage);
+
+    Ok(())
+}
+
+// Errors name the file they happened on and chain their cause
+#[test]
+fn error_display() {
+    use std::error::Error as StdError;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let err = match LogReader::open(temp_dir.path()) {
+        Err(err) => err,
+        Ok(_) => panic!("expected the log to be missing"),
+    };
+    let log_path = temp_dir.path().join("log.data");
+    assert!(err
+        .to_string()
+        .starts_with(&format!("{}: ", log_path.display())));
+    assert!(err.source().is_some());
+
+    let err = Error::KeyNotFound("key1".to_owned());
+    assert_eq!(err.to_string(), "key not found: key1");
+    assert!(err.source().is_none());
+}